@@ -4,4 +4,11 @@ import { svelte } from '@sveltejs/vite-plugin-svelte'
 // https://vitejs.dev/config/
 export default defineConfig({
   plugins: [svelte()],
+  // the dev server forwards to a local ws-server, which serves the built frontend itself
+  server: {
+    proxy: {
+      '/api': 'http://localhost:3030',
+      '/socket': { target: 'ws://localhost:3030', ws: true },
+    },
+  },
 })
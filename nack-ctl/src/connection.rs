@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use nack_protocol::{AuthRequestBody, ErrorBody, ErrorCode, Request, Response};
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+
+/// Websocket connection authenticated with the key of an admin account
+pub struct Connection {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    last_request_id: u64,
+}
+
+/// Request sent to the server, which puts its `request_id` and action in the errors it causes
+#[derive(Debug)]
+pub struct PendingRequest {
+    request_id: String,
+    action: String,
+}
+
+impl Connection {
+    pub async fn open(url: &str, name: &str, key: &str, ca_bundle: Option<&Path>) -> Result<Connection, String> {
+        let url = format!("{}/socket/{}", url.trim_end_matches('/'), name);
+
+        let connector = match ca_bundle {
+            Some(path) => Some(Connector::Rustls(Arc::new(client_config(path)?))),
+            None => None,
+        };
+
+        let (socket, _) = connect_async_tls_with_config(url.as_str(), None, connector)
+            .await
+            .map_err(|e| format!("unable to connect to {}: {}", url, e))?;
+
+        let mut connection = Connection { socket, last_request_id: 0 };
+
+        // the server confirms the authentication, or answers a wrong key with an unauthorized error
+        let pending = connection.send(Request::AuthRequest(AuthRequestBody {
+            app_key: key.to_string(),
+            machine_id: None,
+            labels: Vec::new(),
+            inventory: None,
+            capabilities: None,
+        })).await?;
+        connection.wait_for(&pending, |response| match response {
+            Response::Authenticated(_) => Some(()),
+            _ => None,
+        }).await?;
+
+        Ok(connection)
+    }
+
+    /// Send a request along with a `request_id` unique to this connection
+    pub async fn send(&mut self, request: Request) -> Result<PendingRequest, String> {
+        self.last_request_id += 1;
+        let pending = PendingRequest {
+            request_id: self.last_request_id.to_string(),
+            action: request.to_string(),
+        };
+
+        self.socket
+            .send(Message::text(with_request_id(&request, &pending.request_id)))
+            .await
+            .map_err(|e| format!("unable to send {}: {}", request, e))?;
+        Ok(pending)
+    }
+
+    /// Next message sent by the server, pings are answered along the way
+    pub async fn next(&mut self) -> Result<Response, String> {
+        loop {
+            let message = match self.socket.next().await {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => return Err(format!("connection error: {}", e)),
+                None => return Err("connection closed by the server".to_string()),
+            };
+
+            match message {
+                Message::Text(text) => {
+                    return serde_json::from_str(&text).map_err(|e| format!("invalid message from the server: {}", e));
+                }
+                Message::Close(_) => return Err("connection closed by the server".to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Wait for the response picked by `select`, failing on an error caused by the pending request.
+    /// The errors caused by other requests are only reported
+    pub async fn wait_for<T>(&mut self, pending: &PendingRequest, select: impl Fn(Response) -> Option<T>) -> Result<T, String> {
+        loop {
+            match self.next().await? {
+                Response::Error(data) => match pending.failure(&data) {
+                    Some(failure) => return Err(failure),
+                    None => report_error(&data),
+                },
+                response => {
+                    if let Some(selected) = select(response) {
+                        return Ok(selected);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl PendingRequest {
+    /// Why the request failed, when the error was caused by it
+    fn failure(&self, error: &ErrorBody) -> Option<String> {
+        // errors sent before the request could be parsed may lack its request_id
+        let caused = match &error.request_id {
+            Some(request_id) => *request_id == self.request_id,
+            None => error.action.as_deref() == Some(self.action.as_str()),
+        };
+        if !caused {
+            return None;
+        }
+
+        Some(match error.code {
+            ErrorCode::Unauthorized => format!("authentication failed, check the admin key: {}", error.message),
+            ErrorCode::PermissionDenied => format!("permission denied for {}: {}", self.action, error.message),
+            _ => error.message.clone(),
+        })
+    }
+}
+
+/// JSON of a request, with a `request_id` next to its action
+fn with_request_id(request: &Request, request_id: &str) -> String {
+    let mut message = serde_json::to_value(request).unwrap();
+    message["request_id"] = Value::from(request_id);
+    message.to_string()
+}
+
+/// Error sent by the server which the current command doesn't depend on
+pub fn report_error(error: &ErrorBody) {
+    match &error.action {
+        Some(action) => eprintln!("Warning: {} failed ({}): {}", action, error.code, error.message),
+        None => eprintln!("Warning: {} error: {}", error.code, error.message),
+    }
+}
+
+fn client_config(ca_bundle: &Path) -> Result<ClientConfig, String> {
+    let file = File::open(ca_bundle).map_err(|e| format!("unable to read {}: {}", ca_bundle.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("unable to parse {}: {}", ca_bundle.display(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(format!("no certificate found in {}", ca_bundle.display()));
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending() -> PendingRequest {
+        PendingRequest {
+            request_id: "2".to_string(),
+            action: "run_request".to_string(),
+        }
+    }
+
+    fn error(code: ErrorCode, action: Option<&str>, request_id: Option<&str>) -> ErrorBody {
+        ErrorBody {
+            code,
+            message: "Role viewer can't run exec".to_string(),
+            action: action.map(str::to_string),
+            request_id: request_id.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn failure_of_the_pending_request() {
+        let failure = pending().failure(&error(ErrorCode::PermissionDenied, Some("run_request"), Some("2")));
+        assert_eq!(failure.as_deref(), Some("permission denied for run_request: Role viewer can't run exec"));
+
+        let failure = pending().failure(&error(ErrorCode::Unauthorized, None, Some("2")));
+        assert!(failure.unwrap().starts_with("authentication failed, check the admin key: "));
+
+        // errors of requests which couldn't be parsed may only have their action
+        assert!(pending().failure(&error(ErrorCode::InvalidBody, Some("run_request"), None)).is_some());
+    }
+
+    #[test]
+    fn errors_of_other_requests() {
+        // such as a denied subscription while waiting for the results of a run
+        assert_eq!(pending().failure(&error(ErrorCode::PermissionDenied, Some("subscribe_job_request"), Some("1"))), None);
+        assert_eq!(pending().failure(&error(ErrorCode::InvalidBody, Some("run_request"), Some("1"))), None);
+        assert_eq!(pending().failure(&error(ErrorCode::ParseError, None, None)), None);
+    }
+
+    #[test]
+    fn request_ids() {
+        let message: Value = serde_json::from_str(&with_request_id(&Request::GetClientsRequest, "1")).unwrap();
+        assert_eq!(message, serde_json::json!({"action": "get_clients_request", "request_id": "1"}));
+    }
+}
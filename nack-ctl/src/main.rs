@@ -0,0 +1,256 @@
+//! Command line admin tool
+//! Use :
+//! ```sh
+//! export NACK_URL=ws://localhost:3030 NACK_ADMIN_KEY=...
+//! nack-ctl clients
+//! nack-ctl run open_url --params '{"url": "https://example.com"}' --labels 'lab&!laptop' --wait
+//! nack-ctl --json jobs --state failed | jq .data.jobs[].target
+//! ```
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use nack_protocol::{GetJobRequestBody, JobFilter, JobState, JobSubscriptionBody, Request, Response, RunRequestBody, Selector};
+use serde_json::Value;
+
+use connection::Connection;
+use output::Output;
+
+mod connection;
+mod output;
+
+/// Command line admin tool, talking to the server over the same websocket protocol as the admin page
+#[derive(Debug, Parser)]
+#[command(version, about = "Nack admin command line tool")]
+struct Cli {
+    /// URL of the server, wss:// to connect with TLS
+    #[arg(long, env = "NACK_URL", default_value = "ws://localhost:3030")]
+    url: String,
+
+    /// Key of the admin account to authenticate with
+    #[arg(long, env = "NACK_ADMIN_KEY", hide_env_values = true)]
+    key: String,
+
+    /// Name this connection is displayed with in the server logs
+    #[arg(long, env = "NACK_NAME", default_value = "nack-ctl")]
+    name: String,
+
+    /// PEM bundle of the certificate authorities trusted instead of the built-in ones
+    #[arg(long, env = "NACK_CA_BUNDLE")]
+    ca_bundle: Option<PathBuf>,
+
+    /// Print every message as a JSON document on its own line, for scripts
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the logged in clients
+    Clients,
+    /// Run a module on one or several clients
+    Run(RunArgs),
+    /// Show a job
+    Job {
+        job_id: String,
+        /// Wait for the job to finish
+        #[arg(long)]
+        wait: bool,
+    },
+    /// List the most recent jobs
+    Jobs {
+        #[arg(long)]
+        batch_id: Option<String>,
+        /// Name of the admin account which issued the jobs
+        #[arg(long)]
+        admin: Option<String>,
+        #[arg(long)]
+        target: Option<String>,
+        #[arg(long)]
+        module: Option<String>,
+        #[arg(long)]
+        state: Option<JobState>,
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+    /// Print the messages sent to this admin account until interrupted: the clients updates,
+    /// and the progress and results of the jobs it issued
+    Watch,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    module: String,
+
+    /// Params of the module, as a JSON object
+    #[arg(long, default_value = "{}")]
+    params: String,
+
+    /// ID of a client to run the module on, can be repeated
+    #[arg(long = "target", required_unless_present_any = ["all", "labels"], conflicts_with_all = ["all", "labels"])]
+    targets: Vec<String>,
+
+    /// Run the module on every client
+    #[arg(long, conflicts_with = "labels")]
+    all: bool,
+
+    /// Run the module on the clients matching a label expression, such as `lab&!laptop,office`
+    #[arg(long)]
+    labels: Option<String>,
+
+    /// Keep the jobs of offline clients until they come back online
+    #[arg(long)]
+    queue_if_offline: bool,
+
+    /// Seconds after which the queued jobs are dropped
+    #[arg(long)]
+    expires_in: Option<u64>,
+
+    /// Wait for the results of the jobs
+    #[arg(long)]
+    wait: bool,
+
+    /// Seconds to wait for the results before giving up
+    #[arg(long, default_value_t = 300)]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let output = Output::new(cli.json);
+
+    let result = match Connection::open(&cli.url, &cli.name, &cli.key, cli.ca_bundle.as_deref()).await {
+        Ok(mut connection) => run(cli.command, &mut connection, &output).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Run a command, returning whether everything it did succeeded
+async fn run(command: Command, connection: &mut Connection, output: &Output) -> Result<bool, String> {
+    match command {
+        Command::Clients => {
+            let pending = connection.send(Request::GetClientsRequest).await?;
+            let clients = connection.wait_for(&pending, |response| match response {
+                Response::ClientsUpdate(data) => Some(data),
+                _ => None,
+            }).await?;
+            output.response(&Response::ClientsUpdate(clients));
+            Ok(true)
+        }
+        Command::Run(args) => run_module(args, connection, output).await,
+        Command::Job { job_id, wait } => {
+            let request = match wait {
+                true => Request::SubscribeJobRequest(JobSubscriptionBody { job_id: job_id.clone() }),
+                false => Request::GetJobRequest(GetJobRequestBody { job_id: job_id.clone() }),
+            };
+            let pending = connection.send(request).await?;
+
+            let response = connection.wait_for(&pending, |response| {
+                let matches = match &response {
+                    Response::Job(job) => job.job_id == job_id && (!wait || job.state.is_finished()),
+                    Response::RunResponse(result) => result.job_id == job_id,
+                    _ => false,
+                };
+                matches.then_some(response)
+            }).await?;
+            output.response(&response);
+
+            Ok(match response {
+                Response::Job(job) => !job.state.is_finished() || job.state == JobState::Succeeded,
+                Response::RunResponse(result) => result.success,
+                _ => true,
+            })
+        }
+        Command::Jobs { batch_id, admin, target, module, state, limit } => {
+            let pending = connection.send(Request::ListJobsRequest(JobFilter { batch_id, admin, target, module, state, limit })).await?;
+            let jobs = connection.wait_for(&pending, |response| match response {
+                Response::JobList(data) => Some(data),
+                _ => None,
+            }).await?;
+            output.response(&Response::JobList(jobs));
+            Ok(true)
+        }
+        Command::Watch => {
+            // start with the current clients, the server only sends updates afterwards
+            connection.send(Request::GetClientsRequest).await?;
+            loop {
+                let response = connection.next().await?;
+                output.response(&response);
+            }
+        }
+    }
+}
+
+async fn run_module(args: RunArgs, connection: &mut Connection, output: &Output) -> Result<bool, String> {
+    let params: Value = serde_json::from_str(&args.params).map_err(|e| format!("invalid params: {}", e))?;
+
+    let (target, selector) = match (args.targets.as_slice(), args.all, args.labels) {
+        ([target], _, _) => (Some(target.clone()), None),
+        (_, true, _) => (None, Some(Selector::All)),
+        (_, _, Some(labels)) => (None, Some(Selector::Labels(labels))),
+        (targets, _, _) => (None, Some(Selector::Ids(targets.to_vec()))),
+    };
+
+    let request = connection.send(Request::RunRequest(RunRequestBody {
+        target,
+        selector,
+        module: args.module,
+        params,
+        queue_if_offline: args.queue_if_offline,
+        expires_in: args.expires_in,
+        signed: None,
+    })).await?;
+
+    let accepted = connection.wait_for(&request, |response| match response {
+        Response::RunAccepted(data) => Some(data),
+        _ => None,
+    }).await?;
+    let mut success = accepted.rejected.is_empty();
+    let mut pending: BTreeSet<String> = accepted.jobs.keys().cloned().collect();
+    output.response(&Response::RunAccepted(accepted));
+
+    if !args.wait {
+        return Ok(success);
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(args.timeout);
+
+    while !pending.is_empty() {
+        let response = match tokio::time::timeout_at(deadline, connection.next()).await {
+            Ok(response) => response?,
+            Err(_) => return Err(format!("timed out waiting for {} jobs", pending.len())),
+        };
+
+        // results are sent when the client answers, lost and expired jobs only get their final state
+        let succeeded = match &response {
+            Response::RunResponse(result) if pending.remove(&result.job_id) => result.success,
+            Response::Job(job) if job.state.is_finished() && pending.remove(&job.job_id) => job.state == JobState::Succeeded,
+            Response::Error(error) => {
+                connection::report_error(error);
+                continue;
+            }
+            _ => continue,
+        };
+
+        output.response(&response);
+        success &= succeeded;
+    }
+
+    Ok(success)
+}
@@ -0,0 +1,237 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nack_protocol::{ClientInfo, JobRecord, Response};
+
+/// Prints the messages of the server, either for humans or as JSON lines for scripts
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Output {
+        Output { json }
+    }
+
+    pub fn response(&self, response: &Response) {
+        print!("{}", self.format(response));
+    }
+
+    /// Lines printed for a message, each one ending with a newline
+    fn format(&self, response: &Response) -> String {
+        let mut out = String::new();
+        if self.json {
+            writeln!(out, "{}", response.to_json_string()).unwrap();
+            return out;
+        }
+
+        match response {
+            Response::ClientsUpdate(data) => {
+                writeln!(out, "{:<38} {:<16} {:<24} {:<10} {:<8} LABELS", "ID", "NAME", "OS", "LAST SEEN", "LATENCY").unwrap();
+                for client in &data.connected_clients {
+                    write_client(&mut out, client);
+                }
+            }
+            Response::RunAccepted(data) => {
+                writeln!(out, "Batch {} running {}", data.batch_id, data.module).unwrap();
+                for (job_id, job) in &data.jobs {
+                    writeln!(out, "  {} on {} {}", job_id, job.target, job.state).unwrap();
+                }
+                for rejected in &data.rejected {
+                    writeln!(out, "  {} rejected ({}): {}", rejected.target, rejected.code, rejected.reason).unwrap();
+                }
+            }
+            Response::RunResponse(data) => {
+                writeln!(out, "Job {} {}", data.job_id, if data.success { "succeeded" } else { "failed" }).unwrap();
+                write_output(&mut out, &data.output);
+            }
+            Response::Job(job) => write_job(&mut out, job),
+            Response::JobList(data) => {
+                writeln!(out, "{:<22} {:<10} {:<38} {:<10} {:<12} ADMIN", "JOB", "STATE", "TARGET", "MODULE", "CREATED").unwrap();
+                for job in &data.jobs {
+                    writeln!(
+                        out,
+                        "{:<22} {:<10} {:<38} {:<10} {:<12} {}",
+                        job.job_id, job.state.to_string(), job.target, job.module, ago(job.created_at), job.admin,
+                    ).unwrap();
+                }
+            }
+            Response::Run(data) => writeln!(out, "Run {} {}", data.module, data.params).unwrap(),
+            Response::Authenticated(data) => writeln!(out, "Authenticated as {}", data.name).unwrap(),
+            Response::Error(data) => writeln!(out, "Error ({}): {}", data.code, data.message).unwrap(),
+            Response::ShuttingDown(data) => writeln!(out, "Server shutting down, closing connections in {} seconds", data.grace_period).unwrap(),
+        }
+        out
+    }
+}
+
+fn write_client(out: &mut String, client: &ClientInfo) {
+    let os = client.inventory
+        .as_ref()
+        .map(|inventory| match &inventory.os_version {
+            Some(version) => format!("{} {}", inventory.os_family, version),
+            None => inventory.os_family.clone(),
+        })
+        .unwrap_or_default();
+
+    writeln!(
+        out,
+        "{:<38} {:<16} {:<24} {:<10} {:<8} {}",
+        client.id,
+        client.name,
+        truncate(&os, 24),
+        client.last_seen.map(ago).unwrap_or_default(),
+        client.latency_ms.map(|latency| format!("{}ms", latency)).unwrap_or_default(),
+        client.labels.join(","),
+    ).unwrap();
+}
+
+fn write_job(out: &mut String, job: &JobRecord) {
+    writeln!(out, "Job {} {}", job.job_id, job.state).unwrap();
+    writeln!(out, "  {:<9} {}", "batch:", job.batch_id).unwrap();
+    writeln!(out, "  {:<9} {}", "admin:", job.admin).unwrap();
+    writeln!(out, "  {:<9} {}", "target:", job.target).unwrap();
+    writeln!(out, "  {:<9} {} {}", "module:", job.module, job.params).unwrap();
+    writeln!(out, "  {:<9} {}", "created:", ago(job.created_at)).unwrap();
+    if let Some(finished_at) = job.finished_at {
+        writeln!(out, "  {:<9} {}", "finished:", ago(finished_at)).unwrap();
+    }
+    if let Some(output) = &job.output {
+        write_output(out, output);
+    }
+}
+
+fn write_output(out: &mut String, output: &str) {
+    for line in output.lines() {
+        writeln!(out, "  | {}", line).unwrap();
+    }
+}
+
+/// Time elapsed since a timestamp in milliseconds since the unix epoch, such as `3m ago`
+fn ago(timestamp: i64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+    let seconds = (now - timestamp).max(0) / 1000;
+
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+fn truncate(value: &str, length: usize) -> String {
+    match value.char_indices().nth(length) {
+        Some((index, _)) => value[..index].to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{from_value, json, Value};
+
+    use super::*;
+
+    fn lines(json: bool, response: Value) -> Vec<String> {
+        let response: Response = from_value(response).unwrap();
+        Output::new(json).format(&response).lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn json_lines() {
+        let response = json!({"action": "authenticated", "data": {"name": "alice"}});
+        let lines = lines(true, response.clone());
+
+        assert_eq!(lines.len(), 1);
+        assert_eq!(serde_json::from_str::<Value>(&lines[0]).unwrap(), response);
+    }
+
+    #[test]
+    fn clients_table() {
+        let lines = lines(false, json!({"action": "clients_update", "data": {"connected_clients": [
+            {
+                "id": "0f8e7c6d-1b2a-4c3d-9e8f-7a6b5c4d3e2f",
+                "name": "lab-01",
+                "labels": ["lab", "windows"],
+                "latency_ms": 12,
+                "inventory": {
+                    "hostname": null,
+                    "os_family": "windows",
+                    "os_version": "10.0.19045 Enterprise Edition",
+                    "arch": "x86_64",
+                    "client_version": "0.1.0",
+                    "build_hash": null,
+                    "uptime": null,
+                    "modules": [],
+                },
+            },
+            {"id": "5", "name": "printer", "labels": []},
+        ]}}));
+
+        assert_eq!(lines, [
+            "ID                                     NAME             OS                       LAST SEEN  LATENCY  LABELS",
+            "0f8e7c6d-1b2a-4c3d-9e8f-7a6b5c4d3e2f   lab-01           windows 10.0.19045 Enter            12ms     lab,windows",
+            "5                                      printer                                                       ",
+        ]);
+    }
+
+    #[test]
+    fn run_accepted() {
+        let lines = lines(false, json!({"action": "run_accepted", "data": {
+            "batch_id": "b1",
+            "module": "open_url",
+            "jobs": {"j1": {"target": "lab-01", "state": "dispatched", "expires_at": null}},
+            "rejected": [{"target": "lab-02", "code": "no_targets", "reason": "offline"}],
+        }}));
+
+        assert_eq!(lines, ["Batch b1 running open_url", "  j1 on lab-01 dispatched", "  lab-02 rejected (no_targets): offline"]);
+    }
+
+    #[test]
+    fn job_and_output() {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64 - 180_000;
+        let lines = lines(false, json!({"action": "job", "data": {
+            "job_id": "j1",
+            "batch_id": "b1",
+            "admin": "alice",
+            "target": "lab-01",
+            "module": "exec",
+            "params": {"command": "ipconfig"},
+            "state": "failed",
+            "created_at": created_at,
+            "expires_at": null,
+            "dispatched_at": null,
+            "started_at": null,
+            "finished_at": null,
+            "output": "first\nsecond",
+        }}));
+
+        assert_eq!(lines, [
+            "Job j1 failed",
+            "  batch:    b1",
+            "  admin:    alice",
+            "  target:   lab-01",
+            "  module:   exec {\"command\":\"ipconfig\"}",
+            "  created:  3m ago",
+            "  | first",
+            "  | second",
+        ]);
+    }
+
+    #[test]
+    fn error() {
+        let lines = lines(false, json!({"action": "error", "data": {"code": "permission_denied", "message": "Role viewer can't run exec"}}));
+        assert_eq!(lines, ["Error (permission_denied): Role viewer can't run exec"]);
+    }
+
+    #[test]
+    fn durations() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        assert_eq!(ago(now + 5000), "0s ago");
+        assert_eq!(ago(now - 7_200_000), "2h ago");
+        assert_eq!(ago(now - 3 * 86_400_000), "3d ago");
+        assert_eq!(truncate("système", 3), "sys");
+        assert_eq!(truncate("os", 24), "os");
+    }
+}
@@ -0,0 +1,93 @@
+//! Generate an admin key and sign run requests for clients configured with trusted keys
+//! Use :
+//! ```sh
+//! cargo run -p nack-protocol --example sign_run -- keygen admin.key
+//! echo '{"action": "run_request", "data": {"target": "3f2c...", "module": "open_url", "params": {"url": "https://example.com"}}}' \
+//!     | cargo run -p nack-protocol --example sign_run -- sign admin.key 3600
+//! ```
+
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use nack_protocol::{Request, Selector, SignedCommand, SignedRun, ANY_TARGET};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<&str>>()[1..] {
+        ["keygen", key_path] => keygen(key_path),
+        ["sign", key_path, expires_in] => sign(key_path, expires_in),
+        _ => Err("usage: sign_run keygen KEY_FILE | sign KEY_FILE EXPIRES_IN_SECONDS < run_request.json".to_string()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Write a new private key, printing the public key to add to the trusted keys of the clients
+fn keygen(key_path: &str) -> Result<(), String> {
+    let signing_key = SigningKey::generate(&mut OsRng);
+
+    std::fs::write(key_path, STANDARD.encode(signing_key.to_bytes()))
+        .map_err(|e| format!("unable to write {}: {}", key_path, e))?;
+
+    println!("{}", STANDARD.encode(signing_key.verifying_key().to_bytes()));
+    Ok(())
+}
+
+/// Read a run request from stdin and print it along with its signature
+fn sign(key_path: &str, expires_in: &str) -> Result<(), String> {
+    let key = std::fs::read_to_string(key_path).map_err(|e| format!("unable to read {}: {}", key_path, e))?;
+    let key = STANDARD.decode(key.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or(format!("{} doesn't hold a base64 Ed25519 private key", key_path))?;
+    let signing_key = SigningKey::from_bytes(&key);
+
+    let expires_in: i64 = expires_in.parse().map_err(|e| format!("invalid expiry: {}", e))?;
+
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).map_err(|e| e.to_string())?;
+    let mut data = match serde_json::from_str(&input).map_err(|e| format!("invalid run request: {}", e))? {
+        Request::RunRequest(data) => data,
+        request => return Err(format!("expected a run_request, got {}", request)),
+    };
+
+    let targets = match (&data.target, &data.selector) {
+        (Some(target), _) => vec![target.clone()],
+        (None, Some(Selector::Ids(ids))) => ids.clone(),
+        _ => {
+            eprintln!("Signing for any client, the server chooses which ones run the command");
+            vec![ANY_TARGET.to_string()]
+        }
+    };
+
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+
+    let command = serde_json::to_string(&SignedCommand {
+        module: data.module.clone(),
+        params: data.params.clone(),
+        targets,
+        nonce: STANDARD.encode(nonce),
+        expires_at: now + expires_in * 1000,
+    }).unwrap();
+
+    data.signed = Some(SignedRun {
+        signature: STANDARD.encode(signing_key.sign(command.as_bytes()).to_bytes()),
+        public_key: STANDARD.encode(signing_key.verifying_key().to_bytes()),
+        command,
+    });
+
+    println!("{}", Request::RunRequest(data).to_json_string());
+    Ok(())
+}
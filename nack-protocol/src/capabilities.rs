@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum_macros::Display;
+
+/// Modules a client is able to run keyed by their name, advertised when it authenticates
+pub type Capabilities = BTreeMap<String, ModuleSchema>;
+
+/// Parameters accepted by a module, anything else is refused by the server before reaching the client
+/// Use :
+/// ```json
+/// {"params": {"command": {"type": "string", "required": true}, "args": {"type": "string_array", "required": true}}}
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleSchema {
+    pub params: BTreeMap<String, ParamSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamSchema {
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ParamType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    StringArray,
+    Object,
+    Any,
+}
+
+impl ModuleSchema {
+    pub fn new<const N: usize>(params: [(&str, ParamType, bool); N]) -> ModuleSchema {
+        ModuleSchema {
+            params: params
+                .into_iter()
+                .map(|(name, kind, required)| (name.to_string(), ParamSchema { kind, required }))
+                .collect(),
+        }
+    }
+
+    /// Check that `params` is an object holding the required params, with the expected types and nothing else
+    pub fn validate(&self, params: &Value) -> Result<(), String> {
+        let params = params.as_object().ok_or("params must be an object")?;
+
+        if let Some(name) = params.keys().find(|name| !self.params.contains_key(*name)) {
+            return Err(format!("unknown param {}", name));
+        }
+
+        for (name, schema) in &self.params {
+            match params.get(name) {
+                None | Some(Value::Null) if schema.required => return Err(format!("missing param {}", name)),
+                None | Some(Value::Null) => {}
+                Some(value) if !schema.kind.matches(value) => {
+                    return Err(format!("param {} must be of type {}", name, schema.kind));
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ParamType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            ParamType::String => value.is_string(),
+            ParamType::Integer => value.is_i64() || value.is_u64(),
+            ParamType::Number => value.is_number(),
+            ParamType::Boolean => value.is_boolean(),
+            ParamType::StringArray => value.as_array().is_some_and(|values| values.iter().all(Value::is_string)),
+            ParamType::Object => value.is_object(),
+            ParamType::Any => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn exec() -> ModuleSchema {
+        ModuleSchema::new([
+            ("command", ParamType::String, true),
+            ("args", ParamType::StringArray, true),
+            ("timeout", ParamType::Integer, false),
+        ])
+    }
+
+    #[test]
+    fn accepts_valid_params() {
+        assert_eq!(exec().validate(&json!({"command": "ls", "args": ["-l"]})), Ok(()));
+        assert_eq!(exec().validate(&json!({"command": "ls", "args": [], "timeout": 30})), Ok(()));
+        assert_eq!(exec().validate(&json!({"command": "ls", "args": [], "timeout": null})), Ok(()));
+    }
+
+    #[test]
+    fn rejects_params_which_are_not_an_object() {
+        for params in [json!(null), json!("ls"), json!(["ls"]), json!(1)] {
+            assert_eq!(exec().validate(&params), Err("params must be an object".to_string()));
+        }
+    }
+
+    #[test]
+    fn rejects_missing_params() {
+        assert_eq!(exec().validate(&json!({"args": []})), Err("missing param command".to_string()));
+        assert_eq!(exec().validate(&json!({"command": null, "args": []})), Err("missing param command".to_string()));
+    }
+
+    #[test]
+    fn rejects_unknown_params() {
+        assert_eq!(
+            exec().validate(&json!({"command": "ls", "args": [], "shell": "bash"})),
+            Err("unknown param shell".to_string()),
+        );
+    }
+
+    #[test]
+    fn rejects_mistyped_params() {
+        for params in [
+            json!({"command": 1, "args": []}),
+            json!({"command": ["ls"], "args": []}),
+            json!({"command": "ls", "args": "-l"}),
+            json!({"command": "ls", "args": ["-l", 1]}),
+            json!({"command": "ls", "args": [], "timeout": 1.5}),
+            json!({"command": "ls", "args": [], "timeout": "30"}),
+        ] {
+            assert!(exec().validate(&params).is_err(), "{} was accepted", params);
+        }
+    }
+
+    #[test]
+    fn param_types() {
+        assert!(ParamType::Integer.matches(&json!(-1)));
+        assert!(ParamType::Integer.matches(&json!(u64::MAX)));
+        assert!(ParamType::Number.matches(&json!(1.5)));
+        assert!(!ParamType::Number.matches(&json!("1.5")));
+        assert!(ParamType::Boolean.matches(&json!(false)));
+        assert!(ParamType::StringArray.matches(&json!([])));
+        assert!(ParamType::Object.matches(&json!({})));
+        assert!(!ParamType::Object.matches(&json!([])));
+        assert!(ParamType::Any.matches(&json!(null)));
+    }
+}
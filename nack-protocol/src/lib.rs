@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum_macros::{Display, EnumDiscriminants, EnumString};
+
+pub use capabilities::{Capabilities, ModuleSchema, ParamSchema, ParamType};
+pub use selector::{is_valid_label, LabelExpression, Selector};
+pub use signed::{SignedCommand, SignedRun, ANY_TARGET};
+
+mod capabilities;
+mod selector;
+mod signed;
+
+/// Messages sent to the server, either by a client or by an admin
+/// Every message is serialized as :
+/// ```json
+/// {"action": "run_request", "data": {"target": "soft_client", "module": "exec", "params": {"command": "ls", "args": ["-l"]}}}
+/// ```
+/// An optional `request_id` can be added next to `action`, it is sent back in the errors caused by the message
+#[derive(Debug, Clone, Display, EnumDiscriminants, Serialize, Deserialize)]
+#[serde(tag = "action", content = "data", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[strum_discriminants(name(RequestAction), derive(Display, EnumString), strum(serialize_all = "snake_case"))]
+pub enum Request {
+    AuthRequest(AuthRequestBody),
+    GetClientsRequest,
+    RunRequest(RunRequestBody),
+    RunStarted(RunStartedBody),
+    RunResponse(RunResponseBody),
+    SubscribeJobRequest(JobSubscriptionBody),
+    ListJobsRequest(JobFilter),
+    GetJobRequest(GetJobRequestBody),
+    SetLabelsRequest(SetLabelsRequestBody),
+}
+
+/// Messages sent by the server, either to a client or to an admin
+#[derive(Debug, Clone, Display, Serialize, Deserialize)]
+#[serde(tag = "action", content = "data", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Response {
+    Authenticated(AuthenticatedBody),
+    ClientsUpdate(ClientsUpdateBody),
+    RunAccepted(RunAcceptedBody),
+    Run(RunBody),
+    RunResponse(RunResponseBody),
+    JobList(JobListBody),
+    Job(JobRecord),
+    Error(ErrorBody),
+    ShuttingDown(ShuttingDownBody),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthRequestBody {
+    pub app_key: String,
+    /// Persistent identifier of the machine, clients are keyed by it instead of their connection
+    #[serde(default)]
+    pub machine_id: Option<String>,
+    /// Labels set in the client configuration, admins can override them
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub inventory: Option<ClientInventory>,
+    /// Modules the client runs along with their params, the server doesn't check run requests when omitted
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Sent to a connection once its auth request was accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticatedBody {
+    /// Machine ID of a client, or name of an admin
+    pub name: String,
+}
+
+/// Description of the machine a client runs on, reported when it authenticates
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientInventory {
+    pub hostname: Option<String>,
+    /// `linux`, `windows`, `macos`...
+    pub os_family: String,
+    pub os_version: Option<String>,
+    pub arch: String,
+    pub client_version: String,
+    /// Commit the client was built from
+    pub build_hash: Option<String>,
+    /// Seconds since the machine booted, as of the authentication
+    pub uptime: Option<u64>,
+    /// Modules the client accepts to run
+    pub modules: Vec<String>,
+}
+
+/// Either a single `target` client ID or a `selector` must be given
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRequestBody {
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub selector: Option<Selector>,
+    pub module: String,
+    pub params: Value,
+    /// Keep the job until the target comes back online instead of rejecting it
+    #[serde(default)]
+    pub queue_if_offline: bool,
+    /// Seconds after which a queued job is dropped, the server default is used when omitted
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// Signature of the admin, required by clients configured with trusted keys
+    #[serde(default)]
+    pub signed: Option<SignedRun>,
+}
+
+/// Acknowledgement sent to the admin who issued a run request, with one job per targeted client keyed by the job ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunAcceptedBody {
+    pub batch_id: String,
+    pub module: String,
+    pub jobs: BTreeMap<String, JobSummary>,
+    pub rejected: Vec<RejectedTarget>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub target: String,
+    pub state: JobState,
+    pub expires_at: Option<i64>,
+}
+
+/// Client selected by a run request for which no job was created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedTarget {
+    pub target: String,
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+/// Module invocation forwarded by the server to the targeted client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunBody {
+    pub job_id: String,
+    pub module: String,
+    pub params: Value,
+    #[serde(default)]
+    pub signed: Option<SignedRun>,
+}
+
+/// Sent by the client when it starts running a job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunStartedBody {
+    pub job_id: String,
+}
+
+/// Result of a module invocation, sent by the client and relayed to the admin who issued the job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunResponseBody {
+    pub job_id: String,
+    pub module: String,
+    pub params: Value,
+    pub success: bool,
+    pub output: String,
+}
+
+/// Ask to also receive the result of a job issued by another admin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSubscriptionBody {
+    pub job_id: String,
+}
+
+/// Lifecycle of a job, as stored by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Dispatched,
+    Running,
+    Succeeded,
+    Failed,
+    Lost,
+    Expired,
+}
+
+impl JobState {
+    /// Whether the job reached a state it will never leave
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Succeeded | JobState::Failed | JobState::Lost | JobState::Expired)
+    }
+}
+
+/// A job as stored by the server, timestamps are milliseconds since the unix epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub job_id: String,
+    pub batch_id: String,
+    pub admin: String,
+    pub target: String,
+    pub module: String,
+    pub params: Value,
+    pub state: JobState,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub dispatched_at: Option<i64>,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub output: Option<String>,
+    #[serde(default)]
+    pub signed: Option<SignedRun>,
+}
+
+/// Criteria used to list jobs, every field is optional
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobFilter {
+    pub batch_id: Option<String>,
+    pub admin: Option<String>,
+    pub target: Option<String>,
+    pub module: Option<String>,
+    pub state: Option<JobState>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetJobRequestBody {
+    pub job_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobListBody {
+    pub jobs: Vec<JobRecord>,
+}
+
+/// A logged in client, `id` is the one to target in run requests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: String,
+    pub name: String,
+    pub labels: Vec<String>,
+    /// Last time the client was heard from, in milliseconds since the unix epoch
+    #[serde(default)]
+    pub last_seen: Option<i64>,
+    /// Round-trip time of the last ping, only known while the client is connected
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Reported by the client on its last login
+    #[serde(default)]
+    pub inventory: Option<ClientInventory>,
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Override the labels of a client, `null` restores the ones from its configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetLabelsRequestBody {
+    pub client_id: String,
+    pub labels: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientsUpdateBody {
+    pub connected_clients: Vec<ClientInfo>,
+}
+
+/// Sent back for any message which failed, `action` and `request_id` being the ones of the failed message when known
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default)]
+    pub action: Option<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Machine readable reason of an error or of a rejected target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message isn't valid JSON
+    ParseError,
+    UnknownAction,
+    /// Not authenticated, or with an unknown key
+    Unauthorized,
+    /// Authenticated, but the role of the admin doesn't allow it
+    PermissionDenied,
+    /// The data doesn't match the action
+    InvalidBody,
+    TargetOffline,
+    /// The target list is empty or the selector matches no client, online or not
+    NoTargets,
+    UnsupportedModule,
+    InvalidParams,
+    /// The target isn't covered by the signature of the command
+    NotSigned,
+    NotFound,
+    /// Another connection is already logged in with the same machine ID
+    DuplicateClient,
+    /// The connection is already authenticated, as a client or as an admin
+    AlreadyAuthenticated,
+    /// Too many failed authentications from the same address, the connection is closed
+    LockedOut,
+    /// The server is draining its connections before stopping and doesn't take new jobs
+    ShuttingDown,
+    InternalError,
+}
+
+/// Sent to every connection when the server stops, the clients should reconnect with a backoff once it closes the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShuttingDownBody {
+    /// Seconds left to the clients to answer the jobs they are running, after which the connection is closed
+    pub grace_period: u64,
+}
+
+impl Request {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+impl Response {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
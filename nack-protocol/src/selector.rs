@@ -0,0 +1,165 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+const MAX_LABEL_LENGTH: usize = 64;
+
+/// Clients targeted by a run request
+/// Use :
+/// ```json
+/// "all"
+/// {"ids": ["3f2c...", "a91b..."]}
+/// {"labels": "lab&!laptop,office"}
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selector {
+    All,
+    Ids(Vec<String>),
+    Labels(String),
+}
+
+/// Comma separated alternatives of `&` separated labels, each label can be negated with `!`
+/// `lab&!laptop,office` matches clients labelled `lab` but not `laptop`, and clients labelled `office`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelExpression {
+    alternatives: Vec<Vec<LabelTerm>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LabelTerm {
+    negated: bool,
+    label: String,
+}
+
+impl LabelExpression {
+    pub fn matches(&self, labels: &[String]) -> bool {
+        self.alternatives.iter().any(|terms| {
+            terms.iter().all(|term| labels.contains(&term.label) != term.negated)
+        })
+    }
+}
+
+impl FromStr for LabelExpression {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut alternatives = Vec::new();
+
+        for alternative in expression.split(',') {
+            let mut terms = Vec::new();
+
+            for term in alternative.split('&') {
+                let term = term.trim();
+                let (negated, label) = match term.strip_prefix('!') {
+                    Some(label) => (true, label.trim()),
+                    None => (false, term),
+                };
+
+                if !is_valid_label(label) {
+                    return Err(format!("invalid label {:?}", label));
+                }
+
+                terms.push(LabelTerm {
+                    negated,
+                    label: label.to_string(),
+                });
+            }
+
+            alternatives.push(terms);
+        }
+
+        Ok(LabelExpression { alternatives })
+    }
+}
+
+impl fmt::Display for LabelExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alternatives: Vec<String> = self.alternatives
+            .iter()
+            .map(|terms| {
+                terms.iter()
+                    .map(|term| format!("{}{}", if term.negated { "!" } else { "" }, term.label))
+                    .collect::<Vec<String>>()
+                    .join("&")
+            })
+            .collect();
+
+        write!(f, "{}", alternatives.join(","))
+    }
+}
+
+/// Labels are short identifiers made of ASCII letters, digits, `-`, `_`, `.` and `:`
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.len() <= MAX_LABEL_LENGTH
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    fn matches(expression: &str, client_labels: &[&str]) -> bool {
+        LabelExpression::from_str(expression).unwrap().matches(&labels(client_labels))
+    }
+
+    #[test]
+    fn single_label() {
+        assert!(matches("lab", &["lab"]));
+        assert!(matches("lab", &["office", "lab"]));
+        assert!(!matches("lab", &["office"]));
+        assert!(!matches("lab", &[]));
+        // labels are matched as a whole and case sensitively
+        assert!(!matches("lab", &["lab2"]));
+        assert!(!matches("lab", &["LAB"]));
+    }
+
+    #[test]
+    fn conjunction_and_negation() {
+        assert!(matches("lab&windows", &["lab", "windows"]));
+        assert!(!matches("lab&windows", &["lab"]));
+        assert!(matches("lab&!laptop", &["lab"]));
+        assert!(!matches("lab&!laptop", &["lab", "laptop"]));
+        assert!(matches("!laptop", &[]));
+    }
+
+    #[test]
+    fn alternatives() {
+        assert!(matches("lab&!laptop,office", &["office", "laptop"]));
+        assert!(matches("lab&!laptop,office", &["lab"]));
+        assert!(!matches("lab&!laptop,office", &["lab", "laptop"]));
+        assert!(!matches("lab&!laptop,office", &["home"]));
+    }
+
+    #[test]
+    fn whitespace_is_ignored() {
+        assert_eq!(
+            LabelExpression::from_str(" lab & ! laptop , office ").unwrap(),
+            LabelExpression::from_str("lab&!laptop,office").unwrap(),
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let expression = LabelExpression::from_str("lab&!laptop,office").unwrap();
+        assert_eq!(expression.to_string(), "lab&!laptop,office");
+        assert_eq!(LabelExpression::from_str(&expression.to_string()).unwrap(), expression);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expression in ["", "lab,", ",lab", "lab&", "&lab", "!", "lab&&office", "!!lab", "lab office", "lab|office", "café"] {
+            assert!(LabelExpression::from_str(expression).is_err(), "{:?} was accepted", expression);
+        }
+
+        let too_long = "a".repeat(MAX_LABEL_LENGTH + 1);
+        assert!(LabelExpression::from_str(&too_long).is_err());
+        assert!(LabelExpression::from_str(&too_long[1..]).is_ok());
+    }
+}
@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Target allowing any client to run a signed command, leaving the choice of the clients to the server
+pub const ANY_TARGET: &str = "*";
+
+/// Run command signed with the Ed25519 key of an admin, relayed as is by the server
+/// Use :
+/// ```json
+/// {"command": "{\"module\":\"exec\",...}", "public_key": "base64", "signature": "base64"}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRun {
+    /// JSON of a `SignedCommand`, kept as a string so that the signed bytes don't depend on how it is serialized
+    pub command: String,
+    /// Base64 Ed25519 public key of the admin
+    pub public_key: String,
+    /// Base64 Ed25519 signature of `command`
+    pub signature: String,
+}
+
+/// What an admin allows to run, clients refuse it once expired or if they already ran it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedCommand {
+    pub module: String,
+    pub params: Value,
+    /// Machine IDs of the clients allowed to run the command, or `*` for any client
+    pub targets: Vec<String>,
+    /// Random value making every command unique, so that it can't be replayed
+    pub nonce: String,
+    /// Milliseconds since the unix epoch after which the command is refused
+    pub expires_at: i64,
+}
+
+impl SignedRun {
+    /// Parse the signed command, without checking the signature
+    pub fn parse_command(&self) -> Result<SignedCommand, serde_json::Error> {
+        serde_json::from_str(&self.command)
+    }
+}
+
+impl SignedCommand {
+    pub fn allows_target(&self, target: &str) -> bool {
+        self.targets.iter().any(|allowed| allowed == ANY_TARGET || allowed == target)
+    }
+}
@@ -0,0 +1,18 @@
+use std::process::Command;
+
+/// Expose the commit the client is built from as `NACK_BUILD_HASH`, reported to the server
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=NACK_BUILD_HASH={}", hash);
+    }
+
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+}
@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+const MIN_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Delay between reconnection attempts, doubling after every failed one and randomized
+/// so that a whole fleet disconnected at once by a server restart doesn't reconnect at once
+pub struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { attempts: 0 }
+    }
+
+    /// Start over from the shortest delay, once authenticated
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Between half and all of the current delay
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = MIN_DELAY.saturating_mul(1 << self.attempts.min(16)).min(MAX_DELAY);
+        self.attempts = self.attempts.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether a randomized delay is between half and all of `delay`
+    fn jittered(actual: Duration, delay: Duration) -> bool {
+        actual >= delay / 2 && actual <= delay
+    }
+
+    #[test]
+    fn growth() {
+        let mut backoff = Backoff::new();
+        for seconds in [1, 2, 4, 8, 16, 32] {
+            let delay = backoff.next_delay();
+            assert!(jittered(delay, Duration::from_secs(seconds)), "{:?} for {}s", delay, seconds);
+        }
+    }
+
+    #[test]
+    fn capped() {
+        let mut backoff = Backoff::new();
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= MAX_DELAY);
+        }
+        let delay = backoff.next_delay();
+        assert!(jittered(delay, MAX_DELAY), "{:?}", delay);
+    }
+
+    #[test]
+    fn reset() {
+        let mut backoff = Backoff::new();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        let delay = backoff.next_delay();
+        assert!(jittered(delay, MIN_DELAY), "{:?}", delay);
+    }
+}
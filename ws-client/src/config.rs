@@ -0,0 +1,300 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use base64::Engine;
+use clap::Parser;
+use ed25519_dalek::VerifyingKey;
+use nack_protocol::is_valid_label;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use url::Url;
+
+use crate::machine_id;
+use crate::modules::ModuleRegistry;
+use crate::policy::{Policy, PolicyFile};
+
+const DEFAULT_CONFIG_FILE: &str = "ws-client.toml";
+const DEFAULT_MACHINE_ID_FILE: &str = "ws-client.id";
+const DEFAULT_POLICY_FILE: &str = "ws-client.policy.toml";
+const DEFAULT_NONCE_STORE_FILE: &str = "ws-client.nonces";
+const DEFAULT_PING_INTERVAL: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 90;
+
+/// Command line flags, each one can also be set through the matching environment variable
+#[derive(Debug, Parser)]
+#[command(version, about = "Nack websocket client")]
+struct Args {
+    /// Path of the TOML configuration file, defaults to ws-client.toml next to the executable
+    #[arg(short, long, env = "NACK_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Domain (or IP address) of the server
+    #[arg(long, env = "APP_DOMAIN")]
+    domain: Option<String>,
+
+    /// Port of the server
+    #[arg(short, long, env = "APP_PORT")]
+    port: Option<u16>,
+
+    /// Name this client is displayed with
+    #[arg(short, long, env = "APP_USERNAME")]
+    username: Option<String>,
+
+    /// Key used to authenticate against the server
+    #[arg(long, env = "APP_KEY", hide_env_values = true)]
+    app_key: Option<String>,
+
+    /// File storing the persistent machine ID, defaults to ws-client.id next to the executable
+    #[arg(long, env = "MACHINE_ID_PATH")]
+    machine_id_path: Option<PathBuf>,
+
+    /// Path of the TOML policy file restricting the modules the server can run,
+    /// defaults to ws-client.policy.toml next to the executable
+    #[arg(long, env = "NACK_POLICY")]
+    policy: Option<PathBuf>,
+
+    /// Labels used by admins to target this client, comma separated
+    #[arg(long = "label", env = "APP_LABELS", value_delimiter = ',')]
+    labels: Option<Vec<String>>,
+
+    /// Seconds between two pings sent to the server
+    #[arg(long, env = "APP_PING_INTERVAL")]
+    ping_interval: Option<u64>,
+
+    /// Seconds without any message from the server, pongs included, after which the client reconnects
+    #[arg(long, env = "APP_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Connect with wss:// instead of ws://
+    #[arg(long, env = "APP_TLS")]
+    tls: bool,
+
+    /// PEM bundle of the certificate authorities trusted instead of the built-in ones
+    #[arg(long, env = "APP_CA_BUNDLE")]
+    ca_bundle: Option<PathBuf>,
+
+    /// Base64 SHA-256 hashes of the public keys (SPKI) the server certificate must match, comma separated
+    #[arg(long = "pinned-spki", env = "APP_PINNED_SPKI", value_delimiter = ',')]
+    pinned_spki: Option<Vec<String>>,
+
+    /// Base64 Ed25519 public keys of the admins allowed to sign run commands, comma separated.
+    /// Unsigned commands are refused once at least one key is set
+    #[arg(long = "trusted-key", env = "APP_TRUSTED_KEYS", value_delimiter = ',')]
+    trusted_keys: Option<Vec<String>>,
+
+    /// File storing the nonces of the signed commands already run, defaults to ws-client.nonces next to the executable
+    #[arg(long, env = "NONCE_STORE_PATH")]
+    nonce_store_path: Option<PathBuf>,
+}
+
+/// Content of the TOML configuration file, every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    domain: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    app_key: Option<String>,
+    machine_id_path: Option<PathBuf>,
+    policy: Option<PathBuf>,
+    labels: Option<Vec<String>>,
+    ping_interval: Option<u64>,
+    idle_timeout: Option<u64>,
+    tls: Option<bool>,
+    ca_bundle: Option<PathBuf>,
+    pinned_spki: Option<Vec<String>>,
+    trusted_keys: Option<Vec<String>>,
+    nonce_store_path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Missing(&'static str),
+    Invalid(&'static str, String),
+    MachineId(PathBuf, std::io::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "unable to parse {}: {}", path.display(), e),
+            ConfigError::Missing(field) => write!(f, "missing required setting `{}`", field),
+            ConfigError::Invalid(field, reason) => write!(f, "invalid setting `{}`: {}", field, reason),
+            ConfigError::MachineId(path, e) => write!(f, "unable to load machine ID from {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Client configuration, resolved from (by increasing priority) defaults,
+/// the configuration file, environment variables and command line flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub domain: String,
+    pub port: u16,
+    pub username: String,
+    pub app_key: String,
+    pub machine_id: String,
+    pub labels: Vec<String>,
+    pub policy: Policy,
+    pub ping_interval: u64,
+    pub idle_timeout: u64,
+    pub tls: bool,
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 hashes of the public keys the server may use, any certificate chaining to a trusted authority is accepted when empty
+    pub pinned_spki: Vec<[u8; 32]>,
+    pub trusted_keys: Vec<VerifyingKey>,
+    pub nonce_store_path: PathBuf,
+}
+
+impl Config {
+    /// Configuration of a client running the modules of `modules`, each reading its section of the policy file
+    pub fn load(modules: &ModuleRegistry) -> Result<Config, ConfigError> {
+        let args = Args::parse();
+        let file: FileConfig = read_file(args.config.as_ref(), DEFAULT_CONFIG_FILE)?;
+
+        let machine_id_path = args.machine_id_path
+            .or(file.machine_id_path)
+            .unwrap_or_else(|| next_to_executable(DEFAULT_MACHINE_ID_FILE));
+        let machine_id = machine_id::load_or_create(&machine_id_path)
+            .map_err(|e| ConfigError::MachineId(machine_id_path, e))?;
+
+        let policy_file: PolicyFile = read_file(args.policy.or(file.policy).as_ref(), DEFAULT_POLICY_FILE)?;
+        let policy = Policy::from_file(policy_file, modules).map_err(|e| ConfigError::Invalid("policy", e))?;
+
+        let pinned_spki = args.pinned_spki
+            .or(file.pinned_spki)
+            .unwrap_or_default()
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<[u8; 32]>, ConfigError>>()?;
+
+        let trusted_keys = args.trusted_keys
+            .or(file.trusted_keys)
+            .unwrap_or_default()
+            .iter()
+            .map(|key| parse_public_key(key))
+            .collect::<Result<Vec<VerifyingKey>, ConfigError>>()?;
+
+        let config = Config {
+            domain: args.domain
+                .or(file.domain)
+                .ok_or(ConfigError::Missing("domain"))?,
+            port: args.port.or(file.port).unwrap_or(3030),
+            username: args.username
+                .or(file.username)
+                .ok_or(ConfigError::Missing("username"))?,
+            app_key: args.app_key
+                .or(file.app_key)
+                .ok_or(ConfigError::Missing("app_key"))?,
+            machine_id,
+            labels: args.labels.or(file.labels).unwrap_or_default(),
+            policy,
+            ping_interval: args.ping_interval.or(file.ping_interval).unwrap_or(DEFAULT_PING_INTERVAL),
+            idle_timeout: args.idle_timeout.or(file.idle_timeout).unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            tls: args.tls || file.tls.unwrap_or(false),
+            ca_bundle: args.ca_bundle.or(file.ca_bundle),
+            pinned_spki,
+            trusted_keys,
+            nonce_store_path: args.nonce_store_path
+                .or(file.nonce_store_path)
+                .unwrap_or_else(|| next_to_executable(DEFAULT_NONCE_STORE_FILE)),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn socket_url(&self) -> Url {
+        // validated when the configuration is loaded
+        self.parse_socket_url().unwrap()
+    }
+
+    fn parse_socket_url(&self) -> Result<Url, url::ParseError> {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        Url::parse(&format!("{}://{}:{}/socket/{}", scheme, self.domain, self.port, self.username))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.username.is_empty() {
+            return Err(ConfigError::Invalid("username", "must not be empty".to_string()));
+        }
+
+        if self.username.contains('/') {
+            return Err(ConfigError::Invalid("username", "must not contain '/'".to_string()));
+        }
+
+        if self.app_key.is_empty() {
+            return Err(ConfigError::Invalid("app_key", "must not be empty".to_string()));
+        }
+
+        if let Some(label) = self.labels.iter().find(|label| !is_valid_label(label)) {
+            return Err(ConfigError::Invalid("labels", format!("{:?} is not a valid label", label)));
+        }
+
+        if self.ping_interval == 0 {
+            return Err(ConfigError::Invalid("ping_interval", "must be at least 1 second".to_string()));
+        }
+
+        if self.idle_timeout <= self.ping_interval {
+            return Err(ConfigError::Invalid("idle_timeout", "must be longer than ping_interval".to_string()));
+        }
+
+        if let Err(e) = self.parse_socket_url() {
+            return Err(ConfigError::Invalid("domain", e.to_string()));
+        }
+
+        if !self.tls && (self.ca_bundle.is_some() || !self.pinned_spki.is_empty()) {
+            return Err(ConfigError::Invalid("tls", "must be enabled to use ca_bundle or pinned_spki".to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a base64 SHA-256 hash, as printed by
+/// `openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+fn parse_pin(pin: &str) -> Result<[u8; 32], ConfigError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(pin.trim())
+        .ok()
+        .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+        .ok_or_else(|| ConfigError::Invalid("pinned_spki", format!("{:?} is not a base64 SHA-256 hash", pin)))
+}
+
+fn parse_public_key(key: &str) -> Result<VerifyingKey, ConfigError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+        .ok_or_else(|| ConfigError::Invalid("trusted_keys", format!("{:?} is not a base64 Ed25519 public key", key)))
+}
+
+/// Read a TOML file, a missing file is only an error if its path was explicitly given
+fn read_file<T: DeserializeOwned + Default>(path: Option<&PathBuf>, default_file_name: &str) -> Result<T, ConfigError> {
+    let (path, explicit) = match path {
+        Some(path) => (path.clone(), true),
+        None => (next_to_executable(default_file_name), false),
+    };
+
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(ConfigError::Read(path, e)),
+    };
+
+    toml::from_str(&content).map_err(|e| ConfigError::Parse(path, e))
+}
+
+/// The client usually runs as a scheduled task, so look next to the executable rather than in the working directory
+fn next_to_executable(file_name: &str) -> PathBuf {
+    match std::env::current_exe() {
+        Ok(exe) => exe.with_file_name(file_name),
+        Err(_) => PathBuf::from(file_name),
+    }
+}
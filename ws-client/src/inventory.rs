@@ -0,0 +1,92 @@
+use std::process::Command;
+
+use nack_protocol::ClientInventory;
+
+/// Describe this machine and client, `modules` being the ones the local policy lets the server run
+pub fn collect(modules: Vec<String>) -> ClientInventory {
+    ClientInventory {
+        hostname: hostname(),
+        os_family: std::env::consts::OS.to_string(),
+        os_version: os_version(),
+        arch: std::env::consts::ARCH.to_string(),
+        client_version: env!("CARGO_PKG_VERSION").to_string(),
+        build_hash: option_env!("NACK_BUILD_HASH").map(str::to_string),
+        uptime: uptime(),
+        modules,
+    }
+}
+
+fn hostname() -> Option<String> {
+    if let Ok(hostname) = std::env::var("COMPUTERNAME") {
+        return Some(hostname);
+    }
+
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| command_output("hostname", &[]))
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+#[cfg(target_os = "linux")]
+fn os_version() -> Option<String> {
+    let os_release = std::fs::read_to_string("/etc/os-release").ok()?;
+    os_release
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn os_version() -> Option<String> {
+    command_output("sw_vers", &["-productVersion"])
+}
+
+#[cfg(windows)]
+fn os_version() -> Option<String> {
+    command_output("cmd", &["/C", "ver"])
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn os_version() -> Option<String> {
+    command_output("uname", &["-r"])
+}
+
+#[cfg(target_os = "linux")]
+fn uptime() -> Option<u64> {
+    // first field is the uptime in seconds, with a fractional part
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+    let seconds: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Some(seconds as u64)
+}
+
+#[cfg(target_os = "macos")]
+fn uptime() -> Option<u64> {
+    // { sec = 1681651200, usec = 0 } Sun Apr 16 15:20:00 2023
+    let boot_time = command_output("sysctl", &["-n", "kern.boottime"])?;
+    let booted_at: u64 = boot_time.split("sec = ").nth(1)?.split(',').next()?.parse().ok()?;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    now.checked_sub(booted_at)
+}
+
+#[cfg(windows)]
+fn uptime() -> Option<u64> {
+    let milliseconds = command_output("powershell", &["-NoProfile", "-Command", "[Environment]::TickCount64"])?;
+    milliseconds.parse::<u64>().ok().map(|milliseconds| milliseconds / 1000)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn uptime() -> Option<u64> {
+    None
+}
+
+/// Trimmed standard output of a command, if it succeeded
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let output = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!output.is_empty()).then_some(output)
+}
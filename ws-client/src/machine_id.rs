@@ -0,0 +1,21 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use uuid::Uuid;
+
+/// Read the machine ID stored at `path`, generating and storing a new one on first run
+pub fn load_or_create(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(machine_id) if !machine_id.trim().is_empty() => return Ok(machine_id.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let machine_id = Uuid::new_v4().to_string();
+    fs::write(path, &machine_id)?;
+    tracing::info!("Generated machine ID {}", machine_id);
+
+    Ok(machine_id)
+}
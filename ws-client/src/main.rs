@@ -1,14 +1,29 @@
 #![windows_subsystem = "windows"]
 
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use futures::{SinkExt, StreamExt};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_tungstenite::connect_async;
-use url::Url;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
 
+use backoff::Backoff;
+use config::Config;
+use modules::ModuleRegistry;
+use signature::SignatureVerifier;
 use socket_handler::SocketHandler;
 
+mod backoff;
+mod config;
+mod inventory;
+mod machine_id;
+mod modules;
+mod policy;
+mod signature;
 mod socket_handler;
+mod tls;
 
 
 #[tokio::main]
@@ -16,27 +31,54 @@ async fn main() {
     tracing_subscriber::fmt::init();
     tracing::info!("Starting client");
 
+    let modules = Arc::new(ModuleRegistry::builtin());
+
+    let config = match Config::load(&modules) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let connector = match tls::connector(&config) {
+        Ok(connector) => connector,
+        Err(e) => {
+            tracing::error!("Invalid TLS configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // shared by every connection, so that reconnecting doesn't forget the nonces already seen
+    let verifier = match SignatureVerifier::load(config.trusted_keys.clone(), &config.nonce_store_path) {
+        Ok(verifier) => Arc::new(verifier),
+        Err(e) => {
+            tracing::error!("Unable to load nonces: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut backoff = Backoff::new();
     loop {
-        connect().await;
-        tracing::info!("Disconnected... Reconnecting in 5 seconds...");
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        // a server refusing the client is retried as slowly as an unreachable one
+        if connect(config.clone(), connector.clone(), verifier.clone(), modules.clone()).await {
+            backoff.reset();
+        }
+
+        let delay = backoff.next_delay();
+        tracing::info!("Disconnected... Reconnecting in {:.1} seconds...", delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
     }
 }
 
-async fn connect() {
+/// Run a connection until it drops, returning whether the server accepted the client
+async fn connect(config: Arc<Config>, connector: Option<Connector>, verifier: Arc<SignatureVerifier>, modules: Arc<ModuleRegistry>) -> bool {
     //connect async to the socket
-    let socket = match connect_async(
-        Url::parse(&format!(
-            "ws://{}:{}/socket/{}",
-            env!("APP_DOMAIN"),
-            env!("APP_PORT"),
-            env!("APP_USERNAME")
-        )).unwrap()).await {
-
+    let socket = match connect_async_tls_with_config(config.socket_url(), None, connector).await {
         Ok((socket, _)) => socket,
         Err(e) => {
             tracing::error!("Failed to connect to websocket: {}", e);
-            return;
+            return false;
         }
     };
 
@@ -46,32 +88,55 @@ async fn connect() {
     let (tx, rx) = unbounded_channel();
     let mut rx = UnboundedReceiverStream::new(rx);
 
-    // spawn a task to read from the socket
+    // spawn a task to write to the socket, until it closes
     tokio::spawn(async move {
         while let Some(msg) = rx.next().await {
-            client_ws_tx.send(msg).await.unwrap();
+            if let Err(e) = client_ws_tx.send(msg).await {
+                tracing::error!("Unable to send message: {}", e);
+                break;
+            }
         }
     });
 
-    let socket_handler = SocketHandler::new(tx.clone());
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(config.ping_interval));
+    let mut last_seen = Instant::now();
 
-    // processing messages from the socket
-    while let Some(msg) = client_ws_rx.next().await {
-        // verify that the message is valid
-        let msg = match msg {
-            Ok(msg) => msg,
-            Err(e) => {
-                tracing::error!("websocket error: {}", e);
-                break;
-            }
-        };
+    let socket_handler = SocketHandler::new(tx.clone(), config, verifier, modules);
 
-        tracing::debug!("Got: {}", msg);
+    // processing messages from the socket, and pinging the server to notice when the connection silently dropped
+    loop {
+        tokio::select! {
+            msg = client_ws_rx.next() => {
+                // verify that the message is valid
+                let msg = match msg {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        tracing::error!("websocket error: {}", e);
+                        break;
+                    }
+                    None => break,
+                };
+
+                last_seen = Instant::now();
+                tracing::debug!("Got: {}", msg);
+
+                // spawn a task to handle the message using a clone of the socket handler
+                let socket_handler = socket_handler.clone();
+                tokio::spawn(async move {
+                    socket_handler.handle_message(msg).await;
+                });
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > idle_timeout {
+                    tracing::error!("No message from the server for {} seconds", idle_timeout.as_secs());
+                    break;
+                }
 
-        // spawn a task to handle the message using a clone of the socket handler
-        let socket_handler = socket_handler.clone();
-        tokio::spawn(async move {
-            socket_handler.handle_message(msg).await;
-        });
+                let _ = tx.send(Message::Ping(Vec::new()));
+            }
+        }
     }
+
+    socket_handler.is_authenticated()
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+mod exec;
+mod open_url;
+mod play_url;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use nack_protocol::ModuleSchema;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use exec::Exec;
+use open_url::OpenUrl;
+use play_url::PlayUrl;
+
+use crate::policy::ModulePolicy;
+
+/// Something the server can run on this machine, registered under its name in a `ModuleRegistry`
+pub trait Module: Send + Sync {
+    /// Name the server refers to the module with
+    fn name(&self) -> &'static str;
+
+    /// Params advertised to the server
+    fn schema(&self) -> ModuleSchema;
+
+    /// Check the params before running, jobs queued before the client advertised its schema weren't checked by the server
+    fn validate(&self, params: &Value) -> Result<(), String> {
+        self.schema().validate(params)
+    }
+
+    /// What the machine owner lets the server run, built from the section of the policy file named after the module
+    fn policy(&self, section: Option<toml::Value>) -> Result<Arc<dyn ModulePolicy>, String>;
+
+    /// Run a job, returning its output or why it failed
+    fn run(&self, params: Value) -> BoxFuture<'static, Result<String, String>>;
+}
+
+/// Modules this client knows how to run, keyed by name
+pub struct ModuleRegistry {
+    modules: BTreeMap<&'static str, Box<dyn Module>>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> ModuleRegistry {
+        ModuleRegistry {
+            modules: BTreeMap::new(),
+        }
+    }
+
+    /// Every module built into the client
+    pub fn builtin() -> ModuleRegistry {
+        let mut registry = ModuleRegistry::new();
+        registry.register(Exec);
+        registry.register(OpenUrl);
+        registry.register(PlayUrl);
+        registry
+    }
+
+    /// Add a module, replacing the one registered under the same name if any
+    pub fn register(&mut self, module: impl Module + 'static) {
+        self.modules.insert(module.name(), Box::new(module));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Module> {
+        self.modules.get(name).map(Box::as_ref)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Module> {
+        self.modules.values().map(Box::as_ref)
+    }
+}
+
+/// Params of a job as the struct of its module
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, String> {
+    serde_json::from_value(params).map_err(|e| format!("invalid params: {}", e))
+}
+
+/// Run blocking work off the async runtime, a panic being reported as a failure
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, String> + Send + 'static) -> Result<T, String> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| format!("module panicked: {}", e))?
+}
@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use powershell_script::PsScriptBuilder;
+use nack_protocol::{ModuleSchema, ParamType};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{blocking, parse_params, Module};
+use crate::policy::{parse_section, ModulePolicy};
+
+/// Module for running commands on the host machine, the arguments being part of the command
+/// Use :
+/// ```json
+/// {"action": "run_request", "data": {"target": "soft_client", "module": "exec", "params": {"command": "ls -l -a", "args": []}}}
+/// ```
+pub(crate) struct Exec;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExecParams {
+    command: String,
+    /// Not passed to the command yet, so that the policy rejects any
+    args: Vec<String>,
+}
+
+/// The whole command line is checked, `args` being rejected until they are passed to the command
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExecPolicyFile {
+    /// Disabled unless the machine owner explicitly allows it
+    #[serde(default)]
+    enabled: bool,
+    /// Commands allowed as is
+    #[serde(default)]
+    commands: Vec<String>,
+    /// Regular expressions, one of them must match the whole command
+    #[serde(default)]
+    command_patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct ExecPolicy {
+    enabled: bool,
+    commands: Vec<String>,
+    command_patterns: Vec<Regex>,
+}
+
+impl Module for Exec {
+    fn name(&self) -> &'static str {
+        "exec"
+    }
+
+    fn schema(&self) -> ModuleSchema {
+        ModuleSchema::new([
+            ("command", ParamType::String, true),
+            ("args", ParamType::StringArray, true),
+        ])
+    }
+
+    fn policy(&self, section: Option<toml::Value>) -> Result<Arc<dyn ModulePolicy>, String> {
+        let file: ExecPolicyFile = parse_section(self.name(), section)?;
+        let command_patterns = file.command_patterns
+            .iter()
+            .map(|pattern| {
+                // patterns must match the whole command, not only a part of it
+                Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| format!("invalid command pattern {:?}: {}", pattern, e))
+            })
+            .collect::<Result<Vec<Regex>, String>>()?;
+
+        Ok(Arc::new(ExecPolicy {
+            enabled: file.enabled,
+            commands: file.commands,
+            command_patterns,
+        }))
+    }
+
+    fn run(&self, params: Value) -> BoxFuture<'static, Result<String, String>> {
+        async move {
+            let params: ExecParams = parse_params(params)?;
+            blocking(move || params.run()).await
+        }.boxed()
+    }
+}
+
+impl ModulePolicy for ExecPolicy {
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn check(&self, params: &Value) -> Result<(), String> {
+        if !self.enabled {
+            return Err("module disabled".to_string());
+        }
+
+        let command = params.get("command")
+            .and_then(Value::as_str)
+            .ok_or("missing command")?;
+
+        // an allowed command could otherwise be extended once args are passed to it
+        let has_args = params.get("args")
+            .and_then(Value::as_array)
+            .is_some_and(|args| !args.is_empty());
+        if has_args {
+            return Err("args not supported, they must be part of the command".to_string());
+        }
+
+        let allowed = self.commands.iter().any(|allowed| allowed == command)
+            || self.command_patterns.iter().any(|pattern| pattern.is_match(command));
+
+        match allowed {
+            true => Ok(()),
+            false => Err(format!("command {:?} not allowed", command)),
+        }
+    }
+}
+
+impl ExecParams {
+    fn run(&self) -> Result<String, String> {
+        tracing::info!("Running command: {}", self.command);
+        let ps = PsScriptBuilder::new()
+            .no_profile(true)
+            .non_interactive(true)
+            .hidden(true)
+            .print_commands(false)
+            .build();
+
+        let output = ps.run(&self.command).map_err(|e| format!("command failed: {}", e))?;
+        let output = output.stdout().unwrap_or_default();
+
+        tracing::info!("Output: {}", output);
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use nack_protocol::{ModuleSchema, ParamType};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{blocking, parse_params, Module};
+use crate::policy::{parse_section, ModulePolicy, UrlPolicy};
+
+/// Module for opening a URL with the default application of the host machine
+/// Use :
+/// ```json
+/// {"action": "run_request", "data": {"target": "soft_client", "module": "open_url", "params": {"url": "https://www.youtube.com/watch?v=dQw4w9WgXcQ"}}}
+/// ```
+pub(crate) struct OpenUrl;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OpenUrlParams {
+    url: String,
+}
+
+impl Module for OpenUrl {
+    fn name(&self) -> &'static str {
+        "open_url"
+    }
+
+    fn schema(&self) -> ModuleSchema {
+        ModuleSchema::new([("url", ParamType::String, true)])
+    }
+
+    fn policy(&self, section: Option<toml::Value>) -> Result<Arc<dyn ModulePolicy>, String> {
+        Ok(Arc::new(UrlPolicy::from_file(parse_section(self.name(), section)?)))
+    }
+
+    fn run(&self, params: Value) -> BoxFuture<'static, Result<String, String>> {
+        async move {
+            let params: OpenUrlParams = parse_params(params)?;
+
+            tracing::debug!("Opening url: {}", params.url);
+            blocking(move || open::that(&params.url).map_err(|e| format!("unable to open {}: {}", params.url, e))).await?;
+            Ok("success".to_string())
+        }.boxed()
+    }
+}
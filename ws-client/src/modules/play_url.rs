@@ -0,0 +1,62 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use nack_protocol::{ModuleSchema, ParamType};
+use rodio::{OutputStream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{blocking, parse_params, Module};
+use crate::policy::{parse_section, ModulePolicy, UrlPolicy};
+
+/// Module for playing the audio file at a URL on the host machine
+/// Use :
+/// ```json
+/// {"action": "run_request", "data": {"target": "soft_client", "module": "play_url", "params": {"url": "https://drive.google.com/uc?export=download&id=1c50jZNNreeSXeaDOfoaZJ75eV8mScZFc"}}}
+/// ```
+pub(crate) struct PlayUrl;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PlayUrlParams {
+    url: String,
+}
+
+impl Module for PlayUrl {
+    fn name(&self) -> &'static str {
+        "play_url"
+    }
+
+    fn schema(&self) -> ModuleSchema {
+        ModuleSchema::new([("url", ParamType::String, true)])
+    }
+
+    fn policy(&self, section: Option<toml::Value>) -> Result<Arc<dyn ModulePolicy>, String> {
+        Ok(Arc::new(UrlPolicy::from_file(parse_section(self.name(), section)?)))
+    }
+
+    fn run(&self, params: Value) -> BoxFuture<'static, Result<String, String>> {
+        async move {
+            let params: PlayUrlParams = parse_params(params)?;
+
+            tracing::debug!("Opening url: {}", params.url);
+            let file = reqwest::get(&params.url).await.map_err(|e| format!("unable to download {}: {}", params.url, e))?;
+            let bytes = file.bytes().await.map_err(|e| format!("unable to download {}: {}", params.url, e))?;
+
+            // the output stream can't leave the thread it was opened on
+            blocking(move || {
+                let (_stream, handle) = OutputStream::try_default().map_err(|e| format!("no audio output: {}", e))?;
+                let sink = rodio::Sink::try_new(&handle).map_err(|e| format!("no audio output: {}", e))?;
+
+                let source = rodio::Decoder::new(Cursor::new(bytes)).map_err(|e| format!("unable to decode {}: {}", params.url, e))?;
+                sink.append(source);
+
+                sink.sleep_until_end();
+                Ok(())
+            }).await?;
+
+            Ok("success".to_string())
+        }.boxed()
+    }
+}
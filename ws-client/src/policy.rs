@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::modules::ModuleRegistry;
+
+/// Local policy as written in the policy file, restricting what the server can run on this machine.
+/// Each module reads its own section, named after it
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct PolicyFile {
+    sections: BTreeMap<String, toml::Value>,
+}
+
+/// What the local policy lets the server do with one module
+pub trait ModulePolicy: fmt::Debug + Send + Sync {
+    /// Whether the module can run at all, whatever its params
+    fn is_enabled(&self) -> bool;
+
+    /// Check a module invocation against the policy, returning why it is denied
+    fn check(&self, params: &Value) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    modules: BTreeMap<String, Arc<dyn ModulePolicy>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UrlPolicyFile {
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(default = "default_schemes")]
+    schemes: Vec<String>,
+    /// Hosts allowed along with their subdomains, every host is allowed when omitted
+    domains: Option<Vec<String>>,
+}
+
+impl Default for UrlPolicyFile {
+    fn default() -> Self {
+        UrlPolicyFile {
+            enabled: enabled_by_default(),
+            schemes: default_schemes(),
+            domains: None,
+        }
+    }
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn default_schemes() -> Vec<String> {
+    vec!["http".to_string(), "https".to_string()]
+}
+
+/// Policy of the modules taking a `url` param
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    enabled: bool,
+    schemes: Vec<String>,
+    domains: Option<Vec<String>>,
+}
+
+impl Policy {
+    /// Policy of every registered module, a section naming no registered module being an error
+    pub fn from_file(file: PolicyFile, registry: &ModuleRegistry) -> Result<Policy, String> {
+        let mut sections = file.sections;
+
+        let modules = registry
+            .iter()
+            .map(|module| Ok((module.name().to_string(), module.policy(sections.remove(module.name()))?)))
+            .collect::<Result<BTreeMap<String, Arc<dyn ModulePolicy>>, String>>()?;
+
+        if let Some(name) = sections.keys().next() {
+            return Err(format!("unknown module {}", name));
+        }
+
+        Ok(Policy { modules })
+    }
+
+    /// Whether the module can run at all, whatever its params
+    pub fn is_enabled(&self, module: &str) -> bool {
+        self.modules.get(module).is_some_and(|policy| policy.is_enabled())
+    }
+
+    /// Check a module invocation against the policy, returning why it is denied
+    pub fn check(&self, module: &str, params: &Value) -> Result<(), String> {
+        match self.modules.get(module) {
+            Some(policy) => policy.check(params),
+            None => Err(format!("unknown module {}", module)),
+        }
+    }
+}
+
+/// Section of a module in the policy file, its defaults when omitted
+pub fn parse_section<T: DeserializeOwned + Default>(module: &str, section: Option<toml::Value>) -> Result<T, String> {
+    match section {
+        Some(section) => section.try_into().map_err(|e| format!("invalid [{}] section: {}", module, e)),
+        None => Ok(T::default()),
+    }
+}
+
+impl UrlPolicy {
+    pub fn from_file(file: UrlPolicyFile) -> UrlPolicy {
+        UrlPolicy {
+            enabled: file.enabled,
+            schemes: file.schemes.iter().map(|scheme| scheme.to_ascii_lowercase()).collect(),
+            domains: file.domains.map(|domains| domains.iter().map(|domain| domain.to_ascii_lowercase()).collect()),
+        }
+    }
+}
+
+impl ModulePolicy for UrlPolicy {
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn check(&self, params: &Value) -> Result<(), String> {
+        if !self.enabled {
+            return Err("module disabled".to_string());
+        }
+
+        let url = params.get("url")
+            .and_then(Value::as_str)
+            .ok_or("missing url")?;
+        let url = Url::parse(url).map_err(|e| format!("invalid url: {}", e))?;
+
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(format!("scheme {} not allowed", url.scheme()));
+        }
+
+        if let Some(domains) = &self.domains {
+            let host = url.host_str().unwrap_or_default();
+            let allowed = domains.iter().any(|domain| {
+                host == domain || host.strip_suffix(domain.as_str()).is_some_and(|prefix| prefix.ends_with('.'))
+            });
+
+            if !allowed {
+                return Err(format!("domain {} not allowed", host));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn policy(file: &str) -> Result<Policy, String> {
+        Policy::from_file(toml::from_str(file).unwrap(), &ModuleRegistry::builtin())
+    }
+
+    #[test]
+    fn allowed() {
+        let policy = policy(r#"
+            [exec]
+            enabled = true
+            commands = ["ipconfig"]
+            command_patterns = ["shutdown /s /t \\d+"]
+
+            [open_url]
+            domains = ["example.com"]
+        "#).unwrap();
+
+        assert!(policy.is_enabled("exec"));
+        assert_eq!(policy.check("exec", &json!({"command": "ipconfig", "args": []})), Ok(()));
+        assert_eq!(policy.check("exec", &json!({"command": "shutdown /s /t 60", "args": []})), Ok(()));
+        assert_eq!(policy.check("open_url", &json!({"url": "https://www.example.com/page"})), Ok(()));
+        assert_eq!(policy.check("play_url", &json!({"url": "http://anywhere.org/song.mp3"})), Ok(()));
+    }
+
+    #[test]
+    fn denied() {
+        let policy = policy(r#"
+            [exec]
+            enabled = true
+            commands = ["ipconfig"]
+            command_patterns = ["shutdown /s /t \\d+"]
+
+            [open_url]
+            schemes = ["https"]
+            domains = ["example.com"]
+
+            [play_url]
+            enabled = false
+        "#).unwrap();
+
+        assert!(policy.check("exec", &json!({"command": "ipconfig; del *", "args": []})).is_err());
+        // patterns match the whole command
+        assert!(policy.check("exec", &json!({"command": "shutdown /s /t 60; del *", "args": []})).is_err());
+        assert!(policy.check("exec", &json!({"args": []})).is_err());
+        // args aren't passed to the command, an allowed one can't be extended with them
+        assert!(policy.check("exec", &json!({"command": "ipconfig", "args": ["/release"]})).is_err());
+        assert!(policy.check("open_url", &json!({"url": "http://example.com"})).is_err());
+        assert!(policy.check("open_url", &json!({"url": "https://notexample.com"})).is_err());
+        assert!(policy.check("open_url", &json!({"url": "https://example.com.evil.org"})).is_err());
+        assert!(policy.check("open_url", &json!({"url": "not a url"})).is_err());
+        assert!(!policy.is_enabled("play_url"));
+        assert!(policy.check("play_url", &json!({"url": "https://example.com/song.mp3"})).is_err());
+    }
+
+    #[test]
+    fn unknown_module() {
+        let policy = policy("").unwrap();
+        assert!(!policy.is_enabled("format_disk"));
+        assert!(policy.check("format_disk", &json!({})).is_err());
+
+        // a typo in a section name isn't silently ignored
+        assert!(self::policy("[exce]\nenabled = true\n").is_err());
+        assert!(self::policy("[exec]\nenable = true\n").is_err());
+    }
+
+    #[test]
+    fn missing_policy_file() {
+        let policy = Policy::from_file(PolicyFile::default(), &ModuleRegistry::builtin()).unwrap();
+
+        assert!(!policy.is_enabled("exec"));
+        assert!(policy.check("exec", &json!({"command": "ipconfig", "args": []})).is_err());
+        assert_eq!(policy.check("open_url", &json!({"url": "https://example.com"})), Ok(()));
+        assert!(policy.check("open_url", &json!({"url": "file:///etc/passwd"})).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+use nack_protocol::RunBody;
+use serde_json::Value;
+
+/// Checks that run commands were signed by a trusted admin, and that each of them only runs once
+pub struct SignatureVerifier {
+    trusted_keys: Vec<VerifyingKey>,
+    nonces: Mutex<NonceStore>,
+}
+
+/// Nonces of the commands already run, kept on disk until they expire so that restarting doesn't allow replays
+struct NonceStore {
+    path: PathBuf,
+    seen: HashMap<String, i64>,
+}
+
+impl SignatureVerifier {
+    pub fn load(trusted_keys: Vec<VerifyingKey>, nonce_store_path: &Path) -> Result<SignatureVerifier, String> {
+        let seen = match std::fs::read_to_string(nonce_store_path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("unable to parse {}: {}", nonce_store_path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("unable to read {}: {}", nonce_store_path.display(), e)),
+        };
+
+        Ok(SignatureVerifier {
+            trusted_keys,
+            nonces: Mutex::new(NonceStore {
+                path: nonce_store_path.to_path_buf(),
+                seen,
+            }),
+        })
+    }
+
+    /// Module and params to run, taken from the signed command when trusted keys are configured
+    pub fn verify(&self, run: &RunBody, machine_id: &str) -> Result<(String, Value), String> {
+        // signatures are only required once the machine owner trusts some keys
+        if self.trusted_keys.is_empty() {
+            return Ok((run.module.clone(), run.params.clone()));
+        }
+
+        let signed = run.signed.as_ref().ok_or("command isn't signed")?;
+
+        let public_key = decode(&signed.public_key)
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .ok_or("invalid public key")?;
+        if !self.trusted_keys.contains(&public_key) {
+            return Err("public key isn't trusted".to_string());
+        }
+
+        let signature = decode(&signed.signature)
+            .and_then(|signature| Signature::from_slice(&signature).ok())
+            .ok_or("invalid signature")?;
+        public_key
+            .verify_strict(signed.command.as_bytes(), &signature)
+            .map_err(|_| "signature doesn't match the command")?;
+
+        let command = signed.parse_command().map_err(|e| format!("invalid command: {}", e))?;
+
+        if !command.allows_target(machine_id) {
+            return Err("command doesn't target this client".to_string());
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        if command.expires_at <= now {
+            return Err("command expired".to_string());
+        }
+
+        self.nonces.lock().unwrap().insert(&command.nonce, command.expires_at, now)?;
+
+        Ok((command.module, command.params))
+    }
+}
+
+impl NonceStore {
+    /// Record a nonce, failing if it was already used
+    fn insert(&mut self, nonce: &str, expires_at: i64, now: i64) -> Result<(), String> {
+        // expired commands are refused anyway, their nonces are no longer needed
+        self.seen.retain(|_, expires_at| *expires_at > now);
+
+        if self.seen.contains_key(nonce) {
+            return Err("command already ran".to_string());
+        }
+
+        self.seen.insert(nonce.to_string(), expires_at);
+
+        if let Err(e) = std::fs::write(&self.path, serde_json::to_string(&self.seen).unwrap()) {
+            tracing::error!("Unable to save nonces to {}: {}", self.path.display(), e);
+        }
+
+        Ok(())
+    }
+}
+
+fn decode(value: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD.decode(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use nack_protocol::{SignedCommand, SignedRun};
+    use serde_json::json;
+
+    use super::*;
+
+    const MACHINE_ID: &str = "machine";
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+
+    fn admin_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn command(nonce: &str, expires_at: i64) -> SignedCommand {
+        SignedCommand {
+            module: "open_url".to_string(),
+            params: json!({"url": "https://example.com"}),
+            targets: vec![MACHINE_ID.to_string()],
+            nonce: nonce.to_string(),
+            expires_at,
+        }
+    }
+
+    /// Job relaying `command` signed with `key`, its unsigned module and params being ignored by the client
+    fn signed_run(key: &SigningKey, command: &SignedCommand) -> RunBody {
+        let command = serde_json::to_string(command).unwrap();
+        RunBody {
+            job_id: "job".to_string(),
+            module: "exec".to_string(),
+            params: json!({"command": "del *", "args": []}),
+            signed: Some(SignedRun {
+                public_key: encode(key.verifying_key().as_bytes()),
+                signature: encode(&key.sign(command.as_bytes()).to_bytes()),
+                command,
+            }),
+        }
+    }
+
+    fn verifier(dir: &tempfile::TempDir) -> SignatureVerifier {
+        SignatureVerifier::load(vec![admin_key().verifying_key()], &dir.path().join("nonces.json")).unwrap()
+    }
+
+    #[test]
+    fn runs_the_signed_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = signed_run(&admin_key(), &command("n1", now() + 60_000));
+
+        let (module, params) = verifier(&dir).verify(&run, MACHINE_ID).unwrap();
+        assert_eq!(module, "open_url");
+        assert_eq!(params, json!({"url": "https://example.com"}));
+    }
+
+    #[test]
+    fn unsigned_commands_run_without_trusted_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = SignatureVerifier::load(Vec::new(), &dir.path().join("nonces.json")).unwrap();
+        let mut run = signed_run(&admin_key(), &command("n1", now() + 60_000));
+        run.signed = None;
+
+        assert_eq!(verifier.verify(&run, MACHINE_ID).unwrap().0, "exec");
+    }
+
+    #[test]
+    fn rejects_unsigned_and_untrusted_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(&dir);
+
+        let mut unsigned = signed_run(&admin_key(), &command("n1", now() + 60_000));
+        unsigned.signed = None;
+        assert_eq!(verifier.verify(&unsigned, MACHINE_ID), Err("command isn't signed".to_string()));
+
+        let untrusted = signed_run(&SigningKey::from_bytes(&[8; 32]), &command("n2", now() + 60_000));
+        assert_eq!(verifier.verify(&untrusted, MACHINE_ID), Err("public key isn't trusted".to_string()));
+    }
+
+    #[test]
+    fn rejects_tampered_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(&dir);
+
+        let mut tampered = signed_run(&admin_key(), &command("n1", now() + 60_000));
+        let signed = tampered.signed.as_mut().unwrap();
+        signed.command = signed.command.replace("open_url", "exec");
+        assert_eq!(verifier.verify(&tampered, MACHINE_ID), Err("signature doesn't match the command".to_string()));
+
+        let mut garbled = signed_run(&admin_key(), &command("n2", now() + 60_000));
+        garbled.signed.as_mut().unwrap().signature = encode(&[0; 12]);
+        assert_eq!(verifier.verify(&garbled, MACHINE_ID), Err("invalid signature".to_string()));
+    }
+
+    #[test]
+    fn rejects_commands_for_other_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = signed_run(&admin_key(), &command("n1", now() + 60_000));
+
+        assert_eq!(verifier(&dir).verify(&run, "other"), Err("command doesn't target this client".to_string()));
+    }
+
+    #[test]
+    fn rejects_expired_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = signed_run(&admin_key(), &command("n1", now() - 1));
+
+        assert_eq!(verifier(&dir).verify(&run, MACHINE_ID), Err("command expired".to_string()));
+    }
+
+    #[test]
+    fn rejects_reused_nonces() {
+        let dir = tempfile::tempdir().unwrap();
+        let verifier = verifier(&dir);
+        let run = signed_run(&admin_key(), &command("n1", now() + 60_000));
+
+        assert!(verifier.verify(&run, MACHINE_ID).is_ok());
+        assert_eq!(verifier.verify(&run, MACHINE_ID), Err("command already ran".to_string()));
+
+        // another command can't reuse the nonce either
+        let other = signed_run(&admin_key(), &SignedCommand { module: "play_url".to_string(), ..command("n1", now() + 120_000) });
+        assert_eq!(verifier.verify(&other, MACHINE_ID), Err("command already ran".to_string()));
+    }
+
+    #[test]
+    fn nonces_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = signed_run(&admin_key(), &command("n1", now() + 60_000));
+
+        assert!(verifier(&dir).verify(&run, MACHINE_ID).is_ok());
+        assert_eq!(verifier(&dir).verify(&run, MACHINE_ID), Err("command already ran".to_string()));
+    }
+
+    #[test]
+    fn expired_nonces_are_forgotten() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = NonceStore {
+            path: dir.path().join("nonces.json"),
+            seen: HashMap::new(),
+        };
+
+        store.insert("n1", 1_000, 0).unwrap();
+        assert!(store.insert("n1", 1_000, 500).is_err());
+
+        // once expired the command is refused for its expiry, its nonce no longer needs to be kept
+        store.insert("n2", 3_000, 2_000).unwrap();
+        assert!(!store.seen.contains_key("n1"));
+        assert!(store.seen.contains_key("n2"));
+    }
+}
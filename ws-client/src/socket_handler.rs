@@ -1,91 +1,57 @@
-mod exec;
-mod open_url;
-mod play_url;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use std::str::FromStr;
-
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use strum_macros::{Display, EnumString};
+use nack_protocol::{AuthRequestBody, Capabilities, Request, Response, RunBody, RunResponseBody, RunStartedBody};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite::Message;
 
-use exec::Exec;
-use open_url::OpenUrl;
-use play_url::PlayUrl;
-
-#[derive(Debug, Display, EnumString)]
-#[strum(serialize_all = "snake_case")]
-enum Modules {
-    Exec,
-    OpenUrl,
-    PlayUrl,
-}
-
-#[derive(Debug, Display, EnumString)]
-#[strum(serialize_all = "snake_case")]
-enum Actions {
-    Run
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct BasicRequest {
-    action: String,
-    data: BasicRequestData,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct BasicRequestData {
-    module: String,
-    params: Value,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct BasicResponse {
-    action: String,
-    data: Value,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthRequestBody {
-    app_key: String,
-}
-
-impl BasicResponse {
-    fn new(action: String, data: Value) -> BasicResponse {
-        BasicResponse {
-            action,
-            data,
-        }
-    }
-
-    fn to_json_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
-    }
-}
+use crate::config::Config;
+use crate::inventory;
+use crate::modules::ModuleRegistry;
+use crate::signature::SignatureVerifier;
 
 
 #[derive(Clone)]
 pub struct SocketHandler {
     tx: UnboundedSender<Message>,
+    config: Arc<Config>,
+    verifier: Arc<SignatureVerifier>,
+    modules: Arc<ModuleRegistry>,
+    /// Whether the server accepted the auth request of this connection
+    authenticated: Arc<AtomicBool>,
 }
 
 
 impl SocketHandler {
-    pub fn new(tx: UnboundedSender<Message>) -> SocketHandler {
-        let socket_handler = SocketHandler { tx };
+    pub fn new(tx: UnboundedSender<Message>, config: Arc<Config>, verifier: Arc<SignatureVerifier>, modules: Arc<ModuleRegistry>) -> SocketHandler {
+        let socket_handler = SocketHandler {
+            tx,
+            config,
+            verifier,
+            modules,
+            authenticated: Arc::default(),
+        };
         socket_handler.auth_request();
         tracing::info!("SocketHandler created, auth request sent");
         socket_handler
     }
 
     fn auth_request(&self) {
-        let app_key = env!("APP_KEY");
-
-        self.tx.send(Message::text(BasicResponse::new(
-            "auth_request".to_string(),
-            json!({"app_key": app_key})).to_json_string()
-        )).unwrap();
+        // modules disabled by the local policy would be refused anyway
+        let capabilities: Capabilities = self.modules
+            .iter()
+            .filter(|module| self.config.policy.is_enabled(module.name()))
+            .map(|module| (module.name().to_string(), module.schema()))
+            .collect();
+        let modules = capabilities.keys().cloned().collect();
+
+        self.send_request(Request::AuthRequest(AuthRequestBody {
+            app_key: self.config.app_key.clone(),
+            machine_id: Some(self.config.machine_id.clone()),
+            labels: self.config.labels.clone(),
+            inventory: Some(inventory::collect(modules)),
+            capabilities: Some(capabilities),
+        }));
     }
 
     pub async fn handle_message(&self, message: Message) {
@@ -93,31 +59,101 @@ impl SocketHandler {
             return;
         }
 
-        let message: BasicRequest = match serde_json::from_str(message.to_text().unwrap()) {
+        let message: Response = match serde_json::from_str(message.to_text().unwrap()) {
             Ok(parsed_message) => parsed_message,
-            Err(_) => {
-                tracing::error!("Invalid JSON");
+            Err(e) => {
+                tracing::error!("Invalid message: {}", e);
                 return;
-            } // If the message is not a valid JSON, ignore it
+            } // If the message is not a valid response, ignore it
         };
 
-        match Actions::from_str(&*message.action) {
-            Ok(Actions::Run) => self.handle_run_action(message).await,
-            Err(_) => tracing::error!("Invalid action"),
+        match message {
+            Response::Authenticated(data) => {
+                tracing::info!("Authenticated as {}", data.name);
+                self.authenticated.store(true, Ordering::Relaxed);
+            }
+            Response::Run(data) => self.handle_run_action(data).await,
+            Response::Error(data) => tracing::error!("Server error ({}): {}", data.code, data.message),
+            // the connection is kept to answer the running jobs, the server closes it once they are done
+            Response::ShuttingDown(data) => tracing::info!("Server shutting down within {} seconds", data.grace_period),
+            _ => tracing::error!("Unexpected action {}", message),
         }
     }
 
-    async fn handle_run_action(&self, message: BasicRequest) {
-        match Modules::from_str(&*message.data.module) {
-            Ok(Modules::Exec) => self.send_response(Exec::new(message.data.params).run()),
-            Ok(Modules::OpenUrl) => self.send_response(OpenUrl::new(message.data.params).run()),
-            Ok(Modules::PlayUrl) => self.send_response(PlayUrl::new(message.data.params).run().await),
-            _ => tracing::error!("Invalid action"),
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Relaxed)
+    }
+
+    async fn handle_run_action(&self, data: RunBody) {
+        // only run what a trusted admin signed, whatever the server sent along
+        let (module, params) = match self.verifier.verify(&data, &self.config.machine_id) {
+            Ok(command) => command,
+            Err(reason) => {
+                tracing::error!("Job {} rejected: {}", data.job_id, reason);
+                self.send_refusal(data, format!("rejected signature: {}", reason));
+                return;
+            }
+        };
+
+        let module_kind = match self.modules.get(&module) {
+            Some(module_kind) => module_kind,
+            None => {
+                tracing::error!("Job {} asks for unsupported module {}", data.job_id, module);
+                self.send_refusal(data, format!("unsupported module {}", module));
+                return;
+            }
+        };
+
+        // jobs queued before the client advertised its schema weren't checked by the server
+        if let Err(reason) = module_kind.validate(&params) {
+            tracing::error!("Job {} has invalid params: {}", data.job_id, reason);
+            self.send_refusal(data, format!("invalid params: {}", reason));
+            return;
+        }
+
+        // the machine owner has the last word on what the server can run
+        if let Err(reason) = self.config.policy.check(&module, &params) {
+            tracing::error!("Job {} denied by local policy: {}", data.job_id, reason);
+            self.send_refusal(data, format!("denied by local policy: {}", reason));
+            return;
+        }
+
+        self.send_request(Request::RunStarted(RunStartedBody {
+            job_id: data.job_id.clone(),
+        }));
+
+        let (success, output) = match module_kind.run(params.clone()).await {
+            Ok(output) => (true, output),
+            Err(reason) => {
+                tracing::error!("Job {} failed: {}", data.job_id, reason);
+                (false, reason)
+            }
         };
+
+        self.send_request(Request::RunResponse(RunResponseBody {
+            job_id: data.job_id,
+            module,
+            params,
+            success,
+            output,
+        }));
     }
 
-    fn send_response(&self, response: BasicResponse) {
-        self.tx.send(Message::text(response.to_json_string())).unwrap();
+    /// Answer a job which won't run
+    fn send_refusal(&self, data: RunBody, reason: String) {
+        self.send_request(Request::RunResponse(RunResponseBody {
+            job_id: data.job_id,
+            module: data.module,
+            params: data.params,
+            success: false,
+            output: reason,
+        }));
     }
-}
 
+    /// Queue a request for the socket, dropped if the connection already closed
+    fn send_request(&self, request: Request) {
+        if self.tx.send(Message::text(request.to_json_string())).is_err() {
+            tracing::error!("Connection closed, dropping {}", request);
+        }
+    }
+}
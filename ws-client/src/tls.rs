@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::Connector;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::Config;
+
+/// Connector used for wss:// connections, `None` when TLS is disabled
+pub fn connector(config: &Config) -> Result<Option<Connector>, String> {
+    if !config.tls {
+        return Ok(None);
+    }
+
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    // pinned keys are enough to trust the server, which lets self-signed certificates through
+    let client_config = if !config.pinned_spki.is_empty() {
+        builder
+            .with_custom_certificate_verifier(Arc::new(PinnedKeyVerifier {
+                pins: config.pinned_spki.clone(),
+            }))
+            .with_no_client_auth()
+    } else {
+        let roots = match &config.ca_bundle {
+            Some(path) => load_ca_bundle(path)?,
+            None => default_roots(),
+        };
+
+        builder
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    };
+
+    Ok(Some(Connector::Rustls(Arc::new(client_config))))
+}
+
+fn default_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    roots
+}
+
+fn load_ca_bundle(path: &Path) -> Result<RootCertStore, String> {
+    let file = File::open(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("unable to parse {}: {}", path.display(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(&certs);
+    if ignored > 0 {
+        tracing::error!("Ignored {} invalid certificates of {}", ignored, path.display());
+    }
+
+    if added == 0 {
+        return Err(format!("no certificate found in {}", path.display()));
+    }
+
+    Ok(roots)
+}
+
+/// Trust the server certificate if its public key is one of the pinned ones
+struct PinnedKeyVerifier {
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (_, certificate) = X509Certificate::from_der(&end_entity.0)
+            .map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+
+        if !certificate.validity().is_valid() {
+            return Err(rustls::Error::InvalidCertificateData("certificate expired or not yet valid".to_string()));
+        }
+
+        let hash: [u8; 32] = Sha256::digest(certificate.public_key().raw).into();
+        if !self.pins.contains(&hash) {
+            return Err(rustls::Error::InvalidCertificateData("public key doesn't match any pinned key".to_string()));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,151 @@
+//! Throughput of the session registry, built from the server sources as the server is only a binary
+//! Use :
+//! ```sh
+//! cargo bench -p ws-server --bench sessions -- 10000
+//! ```
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use nack_protocol::{ClientsUpdateBody, Response};
+
+use config::SlowConsumerPolicy;
+use outbox::{Outbox, Outgoing};
+use sessions::{ClientSession, Session, SessionRegistry};
+
+// only part of these modules is used here, their tests included
+#[allow(dead_code, unused_imports)]
+#[path = "../src/config.rs"]
+mod config;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/outbox.rs"]
+mod outbox;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/permissions.rs"]
+mod permissions;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/sessions.rs"]
+mod sessions;
+
+/// Messages sent to every session during the fan-out phase
+const BROADCAST_ROUNDS: usize = 10;
+
+/// Sessions simulated when no count is given
+const DEFAULT_SESSIONS: usize = 10_000;
+
+fn main() {
+    tracing_subscriber::fmt::init();
+
+    // cargo passes `--bench` along with the arguments given after `--`
+    let sessions = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_SESSIONS);
+    run(sessions);
+}
+
+/// Run the session registry through the life of `sessions` simulated connections, spread over one thread per core
+/// as the connection tasks of a busy server would, and log the throughput of each phase
+fn run(sessions: usize) {
+    let threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+    let registry = SessionRegistry::new();
+    let usernames: Vec<String> = (0..sessions).map(|i| format!("bench-{}", i)).collect();
+    tracing::info!("Benchmarking {} sessions on {} threads", sessions, threads);
+
+    measure("connect", sessions, || {
+        parallel(&usernames, threads, |username| {
+            let outbox = Outbox::new(BROADCAST_ROUNDS, SlowConsumerPolicy::DropOldest);
+            registry.insert(username, Session::new("bench", None, outbox));
+        });
+    });
+
+    measure("login", sessions, || {
+        parallel(&usernames, threads, |username| {
+            let _ = registry.login_client(username, client_session(username), true);
+        });
+    });
+
+    measure("pong", sessions, || {
+        parallel(&usernames, threads, |username| {
+            registry.update_client(username, |client| client.last_seen = now());
+        });
+    });
+
+    // a clients update is the largest message sent to every admin, sent here to every session
+    let message: Arc<str> = Arc::from(Response::ClientsUpdate(ClientsUpdateBody {
+        connected_clients: registry.map_clients(ClientSession::info).into_iter().take(10).collect(),
+    }).to_json_string());
+
+    measure("fan-out", sessions * BROADCAST_ROUNDS, || {
+        for _ in 0..BROADCAST_ROUNDS {
+            for (_, outbox) in registry.outboxes(&usernames) {
+                let _ = outbox.push(Outgoing::Text(message.clone()));
+            }
+        }
+    });
+
+    measure("client list", sessions * BROADCAST_ROUNDS, || {
+        for _ in 0..BROADCAST_ROUNDS {
+            registry.map_clients(ClientSession::info);
+        }
+    });
+
+    measure("disconnect", sessions, || {
+        parallel(&usernames, threads, |username| {
+            registry.remove(username);
+        });
+    });
+
+    // connections coming and going all at once, as when a whole fleet reconnects after a network outage
+    measure("churn", sessions, || {
+        parallel(&usernames, threads, |username| {
+            let outbox = Outbox::new(BROADCAST_ROUNDS, SlowConsumerPolicy::DropOldest);
+            registry.insert(username, Session::new("bench", None, outbox));
+            let _ = registry.login_client(username, client_session(username), true);
+            registry.remove(username);
+        });
+    });
+}
+
+fn measure(phase: &str, operations: usize, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+
+    tracing::info!(
+        "{:<12} {:>9} operations in {:>9.2?} ({:.0}/s)",
+        phase,
+        operations,
+        elapsed,
+        operations as f64 / elapsed.as_secs_f64(),
+    );
+}
+
+/// Call `f` on every username, split between `threads` threads
+fn parallel(usernames: &[String], threads: usize, f: impl Fn(&str) + Sync) {
+    let chunk_size = usernames.len().div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        for chunk in usernames.chunks(chunk_size) {
+            let f = &f;
+            scope.spawn(move || chunk.iter().for_each(|username| f(username)));
+        }
+    });
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+/// Client using its connection username as machine ID
+fn client_session(username: &str) -> ClientSession {
+    ClientSession {
+        id: username.to_string(),
+        labels: vec!["bench".to_string()],
+        last_seen: now(),
+        latency_ms: None,
+        inventory: None,
+        capabilities: None,
+    }
+}
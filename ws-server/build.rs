@@ -0,0 +1,60 @@
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+
+/// Embed the files of the built admin frontend (`pnpm build` in `frontend/`), or of `NACK_FRONTEND_DIR`,
+/// as `frontend_assets.rs` in the output directory
+fn main() {
+    let dir = std::env::var_os("NACK_FRONTEND_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("../frontend/dist"));
+
+    println!("cargo:rerun-if-env-changed=NACK_FRONTEND_DIR");
+
+    let mut files = Vec::new();
+    if dir.is_dir() {
+        println!("cargo:rerun-if-changed={}", dir.display());
+        collect(&dir, &dir, &mut files);
+    } else {
+        // a path that doesn't exist would be seen as changed on every build, wait for the build directory to appear instead
+        if let Some(parent) = dir.parent().filter(|parent| parent.is_dir()) {
+            println!("cargo:rerun-if-changed={}", parent.display());
+        }
+        println!("cargo:warning=frontend not built, {} doesn't exist", dir.display());
+    }
+    files.sort();
+
+    let mut generated = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for (path, file) in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+
+        // the ETag only has to change along with the content
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write(&fs::read(&file).unwrap());
+
+        generated.push_str(&format!(
+            "    Asset {{ path: {:?}, content: include_bytes!({:?}), etag: \"\\\"{:016x}\\\"\" }},\n",
+            path,
+            file.canonicalize().unwrap().display().to_string(),
+            hasher.finish(),
+        ));
+    }
+    generated.push_str("];\n");
+
+    let out = Path::new(&std::env::var_os("OUT_DIR").unwrap()).join("frontend_assets.rs");
+    fs::write(out, generated).unwrap();
+}
+
+/// Files under `dir` along with their path relative to `root`, with `/` separators
+fn collect(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(root, &path, files);
+        } else {
+            let relative = path.strip_prefix(root).unwrap();
+            let relative: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+            files.push((relative.join("/"), path));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use nack_protocol::{ClientsUpdateBody, ErrorBody, ErrorCode, RunRequestBody};
+use serde::{Deserialize, Serialize};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::requests_handler::{RequestError, RequestsHandler};
+use crate::tls;
+
+const MAX_BODY_LENGTH: u64 = 64 * 1024;
+
+/// Longest a `GET /api/jobs/{id}?wait=` request waits for the job to finish, in seconds
+const MAX_WAIT: u64 = 60;
+
+#[derive(Debug, Default, Deserialize)]
+struct GetJobQuery {
+    /// Seconds to wait for the job to finish before answering
+    #[serde(default)]
+    wait: u64,
+}
+
+/// HTTP API for scripts, authenticated with the key of an admin account as bearer token
+/// Use :
+/// ```sh
+/// curl -H "Authorization: Bearer $ADMIN_KEY" http://localhost:3030/api/clients
+/// curl -H "Authorization: Bearer $ADMIN_KEY" -d '{"target": "3f2c...", "module": "open_url", "params": {"url": "https://example.com"}}' http://localhost:3030/api/jobs
+/// curl -H "Authorization: Bearer $ADMIN_KEY" http://localhost:3030/api/jobs/V1StGXR8_Z5jdHi6B-myT?wait=30
+/// curl -H "Authorization: Bearer $ADMIN_KEY" http://localhost:3030/metrics
+/// ```
+pub fn routes(requests_handler: RequestsHandler) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let requests_handler = warp::any().map(move || requests_handler.clone());
+    let authorization = warp::header::optional::<String>("authorization").and(tls::remote_address());
+
+    // GET /api/clients -> logged in clients
+    let get_clients = warp::path!("api" / "clients")
+        .and(warp::get())
+        .and(authorization.clone())
+        .and(requests_handler.clone())
+        .then(get_clients);
+
+    // POST /api/jobs -> run request
+    let create_jobs = warp::path!("api" / "jobs")
+        .and(warp::post())
+        .and(authorization.clone())
+        .and(warp::body::content_length_limit(MAX_BODY_LENGTH))
+        .and(warp::body::bytes())
+        .and(requests_handler.clone())
+        .then(create_jobs);
+
+    // GET /api/jobs/{id} -> job, waiting for it to finish when asked to
+    let get_job = warp::path!("api" / "jobs" / String)
+        .and(warp::get())
+        .and(authorization.clone())
+        .and(warp::query::<GetJobQuery>())
+        .and(requests_handler.clone())
+        .then(get_job);
+
+    // GET /metrics -> Prometheus metrics, the scraper authenticating as any admin
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(authorization)
+        .and(requests_handler)
+        .then(get_metrics);
+
+    get_clients.or(create_jobs).unify().or(get_job).unify().or(metrics).unify()
+}
+
+async fn get_clients(authorization: Option<String>, address: Option<SocketAddr>, requests_handler: RequestsHandler) -> Response {
+    if let Err(e) = requests_handler.api_admin(authorization.as_deref(), address) {
+        return error_reply("get_clients_request", e);
+    }
+
+    json_reply(&ClientsUpdateBody {
+        connected_clients: requests_handler.client_infos(),
+    }, StatusCode::OK)
+}
+
+async fn create_jobs(authorization: Option<String>, address: Option<SocketAddr>, body: Bytes, requests_handler: RequestsHandler) -> Response {
+    let admin = match requests_handler.api_admin(authorization.as_deref(), address) {
+        Ok(admin) => admin,
+        Err(e) => return error_reply("run_request", e),
+    };
+
+    let data: RunRequestBody = match serde_json::from_slice(&body) {
+        Ok(data) => data,
+        Err(e) => return error_reply("run_request", RequestError::new(ErrorCode::InvalidBody, format!("Invalid run request: {}", e))),
+    };
+
+    match requests_handler.api_run_request(&admin, data).await {
+        Ok(accepted) => json_reply(&accepted, StatusCode::ACCEPTED),
+        Err(e) => error_reply("run_request", e),
+    }
+}
+
+async fn get_job(job_id: String, authorization: Option<String>, address: Option<SocketAddr>, query: GetJobQuery, requests_handler: RequestsHandler) -> Response {
+    if let Err(e) = requests_handler.api_admin(authorization.as_deref(), address) {
+        return error_reply("get_job_request", e);
+    }
+
+    let wait = Duration::from_secs(query.wait.min(MAX_WAIT));
+    match requests_handler.api_get_job(&job_id, wait).await {
+        Ok(job) => json_reply(&job, StatusCode::OK),
+        Err(e) => error_reply("get_job_request", e),
+    }
+}
+
+async fn get_metrics(authorization: Option<String>, address: Option<SocketAddr>, requests_handler: RequestsHandler) -> Response {
+    if let Err(e) = requests_handler.api_admin(authorization.as_deref(), address) {
+        return error_reply("metrics_request", e);
+    }
+
+    requests_handler.render_metrics().into_response()
+}
+
+fn json_reply<T: Serialize>(body: &T, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(body), status).into_response()
+}
+
+fn error_reply(action: &str, error: RequestError) -> Response {
+    let status = match error.code {
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::LockedOut => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+
+    json_reply(&ErrorBody {
+        code: error.code,
+        message: error.message,
+        action: Some(action.to_string()),
+        request_id: None,
+    }, status)
+}
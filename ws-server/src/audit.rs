@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use nack_protocol::{JobState, RejectedTarget};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use crate::permissions::Role;
+use crate::store::{now, Store};
+
+/// Previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Something worth keeping track of, mostly done by an admin
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    ClientLogin {
+        connection: &'a str,
+        client_id: &'a str,
+    },
+    AdminLogin {
+        connection: &'a str,
+        admin: &'a str,
+        role: Role,
+    },
+    AuthFailed {
+        connection: &'a str,
+    },
+    /// Too many bad keys were sent, from `address` when it is known
+    LockedOut {
+        connection: &'a str,
+        address: Option<IpAddr>,
+    },
+    PermissionDenied {
+        connection: &'a str,
+        admin: Option<&'a str>,
+        action: &'a str,
+        reason: &'a str,
+    },
+    RunRequest {
+        admin: &'a str,
+        batch_id: &'a str,
+        module: &'a str,
+        params: &'a Value,
+        /// Targets keyed by job ID
+        jobs: BTreeMap<&'a str, &'a str>,
+        rejected: &'a [RejectedTarget],
+    },
+    JobDispatched {
+        job_id: &'a str,
+        target: &'a str,
+    },
+    JobFinished {
+        job_id: &'a str,
+        state: JobState,
+        output: Option<&'a str>,
+    },
+    LabelsSet {
+        admin: &'a str,
+        client_id: &'a str,
+        labels: Option<&'a [String]>,
+    },
+}
+
+/// A line of the audit log, `hash` covers every other field including the hash of the previous entry
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    at: i64,
+    event: Value,
+    prev_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> String {
+        let unhashed = AuditEntry {
+            seq: self.seq,
+            at: self.at,
+            event: self.event.clone(),
+            prev_hash: self.prev_hash.clone(),
+            hash: None,
+        };
+
+        // object keys are sorted by serde_json, so parsing and serializing an entry again gives the same bytes
+        format!("{:x}", Sha256::digest(serde_json::to_string(&unhashed).unwrap()))
+    }
+}
+
+/// Append-only log of hash-chained JSON lines, the head of the chain is also kept
+/// in the database so that truncating the file can be detected.
+/// Entries are written and synced by a dedicated thread, in the order they were recorded
+#[derive(Clone)]
+pub struct AuditLog {
+    writer: Sender<WriterCommand>,
+}
+
+enum WriterCommand {
+    Record { at: i64, event: Value },
+    /// Answered once every entry recorded before was written
+    Flush(oneshot::Sender<()>),
+}
+
+struct AuditWriter {
+    file: File,
+    store: Store,
+    seq: u64,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Io(PathBuf, std::io::Error),
+    Database(rusqlite::Error),
+    /// The entry at this line doesn't follow the chain
+    Corrupted(usize, String),
+    /// The file doesn't end where the database says it should
+    HeadMismatch(String),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::Io(path, e) => write!(f, "unable to access {}: {}", path.display(), e),
+            AuditError::Database(e) => write!(f, "unable to access the audit head: {}", e),
+            AuditError::Corrupted(line, reason) => write!(f, "line {}: {}", line, reason),
+            AuditError::HeadMismatch(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+impl AuditLog {
+    /// Open the log for appending, continuing the chain from its last entry.
+    /// Fails when the file doesn't end with the entry recorded in the database, as it was truncated,
+    /// deleted or written to by something else, appending to it would hide that
+    pub fn open(path: &Path, store: Store) -> Result<AuditLog, AuditError> {
+        let (seq, hash) = match last_entry(path)? {
+            Some(entry) => (entry.seq, entry.hash.unwrap_or_default()),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        match store.get_audit_head().map_err(AuditError::Database)? {
+            Some((head_seq, head_hash)) if head_seq != seq || head_hash != hash => {
+                return Err(AuditError::HeadMismatch(format!(
+                    "log ends at entry {} but entry {} was the last one written, run verify-audit",
+                    seq, head_seq,
+                )));
+            }
+            None if seq > 0 => return Err(AuditError::HeadMismatch("no entry was recorded in the database".to_string())),
+            _ => {}
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| AuditError::Io(path.to_path_buf(), e))?;
+
+        let (writer, commands) = channel();
+        let mut audit_writer = AuditWriter { file, store, seq, hash };
+        thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || {
+                // stops once every `AuditLog` is dropped
+                for command in commands {
+                    match command {
+                        WriterCommand::Record { at, event } => audit_writer.write(at, event),
+                        WriterCommand::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .map_err(|e| AuditError::Io(path.to_path_buf(), e))?;
+
+        Ok(AuditLog { writer })
+    }
+
+    /// Queue an event, it is timestamped now but written in the background
+    pub fn record(&self, event: AuditEvent) {
+        let command = WriterCommand::Record {
+            at: now(),
+            event: serde_json::to_value(&event).unwrap(),
+        };
+
+        if self.writer.send(command).is_err() {
+            tracing::error!("Audit writer stopped, entry dropped: {:?}", event);
+        }
+    }
+
+    /// Wait for the entries recorded so far to be written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.writer.send(WriterCommand::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+impl AuditWriter {
+    fn write(&mut self, at: i64, event: Value) {
+        let mut entry = AuditEntry {
+            seq: self.seq + 1,
+            at,
+            event,
+            prev_hash: self.hash.clone(),
+            hash: None,
+        };
+        let hash = entry.compute_hash();
+        entry.hash = Some(hash.clone());
+
+        let line = serde_json::to_string(&entry).unwrap() + "\n";
+        if let Err(e) = self.file.write_all(line.as_bytes()).and_then(|_| self.file.sync_data()) {
+            tracing::error!("Unable to write audit entry {}: {}", entry.seq, e);
+            return;
+        }
+
+        if let Err(e) = self.store.set_audit_head(entry.seq, &hash) {
+            tracing::error!("Unable to record audit head {}: {}", entry.seq, e);
+        }
+
+        self.seq = entry.seq;
+        self.hash = hash;
+    }
+}
+
+/// Walk the whole chain and compare its end with the head recorded in the database,
+/// returning the number of entries
+pub fn verify(path: &Path, store: &Store) -> Result<u64, AuditError> {
+    let file = File::open(path).map_err(|e| AuditError::Io(path.to_path_buf(), e))?;
+
+    let mut seq = 0;
+    let mut hash = GENESIS_HASH.to_string();
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| AuditError::Io(path.to_path_buf(), e))?;
+        let entry: AuditEntry = serde_json::from_str(&line)
+            .map_err(|e| AuditError::Corrupted(index + 1, e.to_string()))?;
+
+        if entry.seq != seq + 1 {
+            return Err(AuditError::Corrupted(index + 1, format!("expected entry {}, found {}", seq + 1, entry.seq)));
+        }
+
+        if entry.prev_hash != hash {
+            return Err(AuditError::Corrupted(index + 1, "previous hash doesn't match the previous entry".to_string()));
+        }
+
+        let computed = entry.compute_hash();
+        if entry.hash.as_ref() != Some(&computed) {
+            return Err(AuditError::Corrupted(index + 1, "hash doesn't match the entry content".to_string()));
+        }
+
+        seq = entry.seq;
+        hash = computed;
+    }
+
+    match store.get_audit_head().map_err(AuditError::Database)? {
+        Some((head_seq, _)) if head_seq > seq => Err(AuditError::HeadMismatch(
+            format!("log ends at entry {} but entry {} was written, it was truncated", seq, head_seq)
+        )),
+        Some((head_seq, _)) if head_seq < seq => Err(AuditError::HeadMismatch(
+            format!("log goes on after entry {} which was the last one written", head_seq)
+        )),
+        Some((_, head_hash)) if head_hash != hash => Err(AuditError::HeadMismatch(
+            format!("entry {} differs from the one written", seq)
+        )),
+        None if seq > 0 => Err(AuditError::HeadMismatch("no entry was recorded in the database".to_string())),
+        _ => Ok(seq),
+    }
+}
+
+/// Last entry of the log, without checking the chain
+fn last_entry(path: &Path) -> Result<Option<AuditEntry>, AuditError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(AuditError::Io(path.to_path_buf(), e)),
+    };
+
+    let mut last = None;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| AuditError::Io(path.to_path_buf(), e))?;
+        last = Some((index, line));
+    }
+
+    match last {
+        Some((index, line)) => serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|e| AuditError::Corrupted(index + 1, e.to_string())),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Log of `entries` entries along with its database, in a directory removed once dropped
+    async fn write_log(entries: usize) -> (tempfile::TempDir, PathBuf, Store) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let store = Store::open(&dir.path().join("audit.db")).unwrap();
+
+        let audit = AuditLog::open(&path, store.clone()).unwrap();
+        for i in 0..entries {
+            audit.record(AuditEvent::AuthFailed { connection: &format!("connection-{}", i) });
+        }
+        audit.flush().await;
+
+        (dir, path, store)
+    }
+
+    fn rewrite_lines(path: &Path, f: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(path).unwrap().lines().map(str::to_string).collect();
+        f(&mut lines);
+        fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_intact_log() {
+        let (_dir, path, store) = write_log(3).await;
+        assert_eq!(verify(&path, &store).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn verify_detects_edited_line() {
+        let (_dir, path, store) = write_log(3).await;
+        rewrite_lines(&path, |lines| lines[1] = lines[1].replace("connection-1", "connection-9"));
+
+        assert!(matches!(verify(&path, &store), Err(AuditError::Corrupted(2, _))));
+    }
+
+    #[tokio::test]
+    async fn verify_detects_reordered_lines() {
+        let (_dir, path, store) = write_log(3).await;
+        rewrite_lines(&path, |lines| lines.swap(0, 1));
+
+        assert!(matches!(verify(&path, &store), Err(AuditError::Corrupted(1, _))));
+    }
+
+    #[tokio::test]
+    async fn verify_detects_truncation() {
+        let (_dir, path, store) = write_log(3).await;
+        rewrite_lines(&path, |lines| lines.truncate(2));
+
+        assert!(matches!(verify(&path, &store), Err(AuditError::HeadMismatch(_))));
+    }
+
+    #[tokio::test]
+    async fn open_refuses_truncated_log() {
+        let (_dir, path, store) = write_log(3).await;
+        rewrite_lines(&path, |lines| lines.truncate(2));
+
+        assert!(matches!(AuditLog::open(&path, store.clone()), Err(AuditError::HeadMismatch(_))));
+        // the head wasn't moved back, the truncation is still reported
+        assert!(matches!(verify(&path, &store), Err(AuditError::HeadMismatch(_))));
+    }
+
+    #[tokio::test]
+    async fn open_refuses_deleted_log() {
+        let (_dir, path, store) = write_log(2).await;
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(AuditLog::open(&path, store), Err(AuditError::HeadMismatch(_))));
+    }
+
+    #[test]
+    fn head_is_never_moved_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join("audit.db")).unwrap();
+
+        store.set_audit_head(5, "five").unwrap();
+        store.set_audit_head(3, "three").unwrap();
+        assert_eq!(store.get_audit_head().unwrap(), Some((5, "five".to_string())));
+    }
+}
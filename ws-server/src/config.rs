@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::permissions::{resolve_roles, AdminAccount, Role, RolePermissions, RolePermissionsConfig};
+
+const DEFAULT_CONFIG_PATH: &str = "ws-server.toml";
+const DEFAULT_DATABASE_PATH: &str = "ws-server.db";
+const DEFAULT_AUDIT_LOG_PATH: &str = "ws-server.audit.log";
+const DEFAULT_QUEUE_EXPIRY: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_QUEUE_EXPIRY: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_ADMIN_NAME: &str = "admin";
+const DEFAULT_PING_INTERVAL: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 90;
+const DEFAULT_AUTH_TIMEOUT: u64 = 10;
+const DEFAULT_MAX_AUTH_FAILURES: u32 = 5;
+const DEFAULT_AUTH_LOCKOUT: u64 = 5 * 60;
+const DEFAULT_SEND_QUEUE_SIZE: usize = 256;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: u64 = 30;
+
+/// Command line flags, each one can also be set through the matching environment variable
+#[derive(Debug, Parser)]
+#[command(version, about = "Nack websocket server")]
+struct Args {
+    /// Path of the TOML configuration file
+    #[arg(short, long, env = "NACK_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address the server listens on
+    #[arg(long, env = "BIND_ADDRESS")]
+    bind_address: Option<IpAddr>,
+
+    /// Port the server listens on
+    #[arg(short, long, env = "PORT")]
+    port: Option<u16>,
+
+    /// Key used by clients to authenticate
+    #[arg(long, env = "CLIENT_KEY", hide_env_values = true)]
+    client_key: Option<String>,
+
+    /// Key of the built-in `admin` superuser account, other accounts are set in the configuration file
+    #[arg(long, env = "ADMIN_KEY", hide_env_values = true)]
+    admin_key: Option<String>,
+
+    /// Path of the SQLite database storing the jobs
+    #[arg(long, env = "DATABASE_PATH")]
+    database_path: Option<PathBuf>,
+
+    /// Seconds a job queued for an offline client is kept when the request doesn't specify it
+    #[arg(long, env = "QUEUE_EXPIRY")]
+    queue_expiry: Option<u64>,
+
+    /// Longest expiry a run request can ask for, in seconds, longer ones are shortened to it
+    #[arg(long, env = "MAX_QUEUE_EXPIRY")]
+    max_queue_expiry: Option<u64>,
+
+    /// What to do when a client logs in with the machine ID of an already connected client
+    #[arg(long, env = "DUPLICATE_CLIENT_POLICY", value_enum)]
+    duplicate_client_policy: Option<DuplicateClientPolicy>,
+
+    /// Path of the hash-chained audit log of authentications, run requests and job results
+    #[arg(long, env = "AUDIT_LOG_PATH")]
+    audit_log_path: Option<PathBuf>,
+
+    /// Seconds between two pings sent to every connection
+    #[arg(long, env = "PING_INTERVAL")]
+    ping_interval: Option<u64>,
+
+    /// Seconds without any message, pongs included, after which a connection is closed
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Seconds a connection has to authenticate before it is closed
+    #[arg(long, env = "AUTH_TIMEOUT")]
+    auth_timeout: Option<u64>,
+
+    /// Bad keys sent from the same address before it is locked out, 0 to never lock out
+    #[arg(long, env = "MAX_AUTH_FAILURES")]
+    max_auth_failures: Option<u32>,
+
+    /// Seconds during which a locked out address can't authenticate
+    #[arg(long, env = "AUTH_LOCKOUT")]
+    auth_lockout: Option<u64>,
+
+    /// Messages waiting to be sent to a connection before the slow consumer policy applies
+    #[arg(long, env = "SEND_QUEUE_SIZE")]
+    send_queue_size: Option<usize>,
+
+    /// What to do when a connection doesn't read its messages fast enough to keep its queue from filling up
+    #[arg(long, env = "SLOW_CONSUMER_POLICY", value_enum)]
+    slow_consumer_policy: Option<SlowConsumerPolicy>,
+
+    /// Seconds the clients have to answer the jobs in flight once the server is asked to stop, before it closes the connections
+    #[arg(long, env = "SHUTDOWN_GRACE_PERIOD")]
+    shutdown_grace_period: Option<u64>,
+
+    /// Base URL the admin page reaches the server at, such as https://nack.example.com, taken from the request when unset
+    #[arg(long, env = "PUBLIC_URL")]
+    public_url: Option<String>,
+
+    /// PEM certificate chain, the server listens for wss:// connections when it is set along with the key
+    #[arg(long, env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "TLS_KEY_PATH")]
+    tls_key_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Maintenance commands, the server is started when none is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Check that the audit log was neither modified nor truncated, then exit
+    VerifyAudit,
+}
+
+/// Content of the TOML configuration file, every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind_address: Option<IpAddr>,
+    port: Option<u16>,
+    client_key: Option<String>,
+    admin_key: Option<String>,
+    database_path: Option<PathBuf>,
+    queue_expiry: Option<u64>,
+    max_queue_expiry: Option<u64>,
+    duplicate_client_policy: Option<DuplicateClientPolicy>,
+    audit_log_path: Option<PathBuf>,
+    ping_interval: Option<u64>,
+    idle_timeout: Option<u64>,
+    auth_timeout: Option<u64>,
+    max_auth_failures: Option<u32>,
+    auth_lockout: Option<u64>,
+    send_queue_size: Option<usize>,
+    slow_consumer_policy: Option<SlowConsumerPolicy>,
+    shutdown_grace_period: Option<u64>,
+    public_url: Option<String>,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    admins: Option<Vec<AdminAccount>>,
+    roles: Option<HashMap<Role, RolePermissionsConfig>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateClientPolicy {
+    /// Close the existing connection and keep the new one
+    #[default]
+    Replace,
+    /// Keep the existing connection and refuse the new one
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room for the new one
+    DropOldest,
+    /// Close the connection, which would otherwise miss messages without knowing it
+    #[default]
+    Disconnect,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Missing(&'static str),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "unable to parse {}: {}", path.display(), e),
+            ConfigError::Missing(field) => write!(f, "missing required setting `{}`", field),
+            ConfigError::Invalid(field, reason) => write!(f, "invalid setting `{}`: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Server configuration, resolved from (by increasing priority) defaults,
+/// the configuration file, environment variables and command line flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub client_key: String,
+    pub admins: Vec<AdminAccount>,
+    pub roles: HashMap<Role, RolePermissions>,
+    pub database_path: PathBuf,
+    pub queue_expiry: u64,
+    pub max_queue_expiry: u64,
+    pub duplicate_client_policy: DuplicateClientPolicy,
+    pub audit_log_path: PathBuf,
+    pub ping_interval: u64,
+    pub idle_timeout: u64,
+    pub auth_timeout: u64,
+    pub max_auth_failures: u32,
+    pub auth_lockout: u64,
+    pub send_queue_size: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    pub shutdown_grace_period: u64,
+    pub public_url: Option<String>,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub command: Option<Command>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_args(Args::parse())
+    }
+
+    /// Configuration given by command line flags alone, as tests set it up
+    #[cfg(test)]
+    pub fn from_flags(flags: &[&str]) -> Result<Config, ConfigError> {
+        let mut args = Args::parse_from(std::iter::once("ws-server").chain(flags.iter().copied()));
+        // a configuration file in the working directory isn't part of the test
+        args.config.get_or_insert_with(|| PathBuf::from("/dev/null"));
+        Config::from_args(args)
+    }
+
+    fn from_args(args: Args) -> Result<Config, ConfigError> {
+        let file = read_file(args.config.as_ref())?;
+
+        let mut admins = file.admins.unwrap_or_default();
+        if let Some(admin_key) = args.admin_key.or(file.admin_key) {
+            admins.push(AdminAccount {
+                name: DEFAULT_ADMIN_NAME.to_string(),
+                key: admin_key,
+                role: Role::Superuser,
+            });
+        }
+
+        let roles = resolve_roles(file.roles.unwrap_or_default())
+            .map_err(|e| ConfigError::Invalid("roles", e))?;
+
+        let config = Config {
+            bind_address: args.bind_address
+                .or(file.bind_address)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: args.port.or(file.port).unwrap_or(3030),
+            client_key: args.client_key.or(file.client_key).unwrap_or_default(),
+            admins,
+            roles,
+            database_path: args.database_path
+                .or(file.database_path)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE_PATH)),
+            queue_expiry: args.queue_expiry.or(file.queue_expiry).unwrap_or(DEFAULT_QUEUE_EXPIRY),
+            max_queue_expiry: args.max_queue_expiry.or(file.max_queue_expiry).unwrap_or(DEFAULT_MAX_QUEUE_EXPIRY),
+            duplicate_client_policy: args.duplicate_client_policy
+                .or(file.duplicate_client_policy)
+                .unwrap_or_default(),
+            audit_log_path: args.audit_log_path
+                .or(file.audit_log_path)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_AUDIT_LOG_PATH)),
+            ping_interval: args.ping_interval.or(file.ping_interval).unwrap_or(DEFAULT_PING_INTERVAL),
+            idle_timeout: args.idle_timeout.or(file.idle_timeout).unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            auth_timeout: args.auth_timeout.or(file.auth_timeout).unwrap_or(DEFAULT_AUTH_TIMEOUT),
+            max_auth_failures: args.max_auth_failures.or(file.max_auth_failures).unwrap_or(DEFAULT_MAX_AUTH_FAILURES),
+            auth_lockout: args.auth_lockout.or(file.auth_lockout).unwrap_or(DEFAULT_AUTH_LOCKOUT),
+            send_queue_size: args.send_queue_size.or(file.send_queue_size).unwrap_or(DEFAULT_SEND_QUEUE_SIZE),
+            slow_consumer_policy: args.slow_consumer_policy
+                .or(file.slow_consumer_policy)
+                .unwrap_or_default(),
+            shutdown_grace_period: args.shutdown_grace_period
+                .or(file.shutdown_grace_period)
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD),
+            public_url: args.public_url.or(file.public_url),
+            tls_cert_path: args.tls_cert_path.or(file.tls_cert_path),
+            tls_key_path: args.tls_key_path.or(file.tls_key_path),
+            command: args.command,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn is_client_key(&self, key: &str) -> bool {
+        keys_match(&self.client_key, key)
+    }
+
+    /// Admin account of a key, every account being compared so that the time taken doesn't tell which one matched
+    pub fn find_admin(&self, key: &str) -> Option<&AdminAccount> {
+        let mut found = None;
+        for admin in &self.admins {
+            if keys_match(&admin.key, key) {
+                found.get_or_insert(admin);
+            }
+        }
+        found
+    }
+
+    pub fn permissions(&self, role: Role) -> &RolePermissions {
+        // every role is resolved when the configuration is loaded
+        &self.roles[&role]
+    }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// Certificate and key paths, when TLS is enabled
+    pub fn tls_paths(&self) -> Option<(&Path, &Path)> {
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path, key_path)),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // the keys are only needed to serve, not to run a maintenance command
+        if self.command.is_some() {
+            return Ok(());
+        }
+
+        if self.client_key.is_empty() {
+            return Err(ConfigError::Missing("client_key"));
+        }
+
+        if i64::try_from(self.max_queue_expiry).ok().and_then(|max| max.checked_mul(1000)).is_none() {
+            return Err(ConfigError::Invalid("max_queue_expiry", "is out of range".to_string()));
+        }
+
+        if self.queue_expiry > self.max_queue_expiry {
+            return Err(ConfigError::Invalid("queue_expiry", "must not be longer than max_queue_expiry".to_string()));
+        }
+
+        if self.ping_interval == 0 {
+            return Err(ConfigError::Invalid("ping_interval", "must be at least 1 second".to_string()));
+        }
+
+        if self.idle_timeout <= self.ping_interval {
+            return Err(ConfigError::Invalid("idle_timeout", "must be longer than ping_interval".to_string()));
+        }
+
+        if self.send_queue_size == 0 {
+            return Err(ConfigError::Invalid("send_queue_size", "must be at least 1".to_string()));
+        }
+
+        if self.auth_timeout == 0 {
+            return Err(ConfigError::Invalid("auth_timeout", "must be at least 1 second".to_string()));
+        }
+
+        if self.public_url.as_ref().is_some_and(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+            return Err(ConfigError::Invalid("public_url", "must start with http:// or https://".to_string()));
+        }
+
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => return Err(ConfigError::Missing("tls_key_path")),
+            (None, Some(_)) => return Err(ConfigError::Missing("tls_cert_path")),
+            _ => {}
+        }
+
+        if self.admins.is_empty() {
+            return Err(ConfigError::Missing("admins"));
+        }
+
+        for (index, admin) in self.admins.iter().enumerate() {
+            if admin.name.is_empty() {
+                return Err(ConfigError::Invalid("admins", "names must not be empty".to_string()));
+            }
+
+            if admin.key.is_empty() {
+                return Err(ConfigError::Invalid("admins", format!("key of {} must not be empty", admin.name)));
+            }
+
+            if admin.key == self.client_key {
+                return Err(ConfigError::Invalid("admins", format!("key of {} must differ from client_key", admin.name)));
+            }
+
+            for other in &self.admins[..index] {
+                if other.name == admin.name {
+                    return Err(ConfigError::Invalid("admins", format!("{} is defined twice", admin.name)));
+                }
+
+                if other.key == admin.key {
+                    return Err(ConfigError::Invalid("admins", format!("{} and {} share the same key", other.name, admin.name)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the configuration file, a missing file is only an error if its path was explicitly given
+fn read_file(path: Option<&PathBuf>) -> Result<FileConfig, ConfigError> {
+    let (path, explicit) = match path {
+        Some(path) => (path.clone(), true),
+        None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => return Ok(FileConfig::default()),
+        Err(e) => return Err(ConfigError::Read(path, e)),
+    };
+
+    toml::from_str(&content).map_err(|e| ConfigError::Parse(path, e))
+}
+
+/// Compare a configured key with one sent by a peer in constant time, hashing them first so that their length doesn't leak either
+fn keys_match(expected: &str, key: &str) -> bool {
+    Sha256::digest(expected.as_bytes()).ct_eq(&Sha256::digest(key.as_bytes())).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_exactly() {
+        assert!(keys_match("secret", "secret"));
+        assert!(!keys_match("secret", "Secret"));
+        assert!(!keys_match("secret", "secret "));
+        assert!(!keys_match("secret", "secre"));
+        assert!(!keys_match("secret", ""));
+    }
+
+    #[test]
+    fn finds_the_admin_of_a_key() {
+        let config = Config::from_flags(&["--client-key", "ck", "--admin-key", "ak"]).unwrap();
+
+        assert!(config.is_client_key("ck"));
+        assert!(!config.is_client_key("ak"));
+        assert_eq!(config.find_admin("ak").map(|admin| admin.name.as_str()), Some(DEFAULT_ADMIN_NAME));
+        assert!(config.find_admin("ck").is_none());
+        assert!(config.find_admin("").is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use warp::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG};
+use warp::http::StatusCode;
+use warp::hyper::Body;
+use warp::path::Tail;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::Config;
+
+// `ASSETS`, generated by build.rs from the built frontend
+include!(concat!(env!("OUT_DIR"), "/frontend_assets.rs"));
+
+/// Vite puts the files with a content hash in their name under `assets/`, they can be cached forever
+const IMMUTABLE_PREFIX: &str = "assets/";
+
+/// Served at `/` when the server was built without the frontend
+const NOT_BUILT_HTML: &str = "<!DOCTYPE html><html><body><h1>Nack</h1>\
+    <p>The admin page isn't part of this build: run <code>pnpm build</code> in <code>frontend/</code>, then build ws-server again.</p>\
+    </body></html>";
+
+/// File of the built frontend
+pub struct Asset {
+    /// Path relative to the build directory, with `/` separators
+    path: &'static str,
+    content: &'static [u8],
+    etag: &'static str,
+}
+
+/// What the admin page needs to know to reach the server, as it can be served from anywhere during development
+#[derive(Debug, Serialize)]
+struct FrontendConfig {
+    /// URL of the websocket endpoint, to append the connection name to
+    socket_url: String,
+}
+
+/// Admin page embedded in the binary, along with the configuration it discovers the server with
+pub fn routes(config: Arc<Config>) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    // GET /api/config -> websocket URL of this server, as reached by the browser
+    let frontend_config = warp::path!("api" / "config")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .map(move |host: Option<String>, proto: Option<String>| {
+            warp::reply::json(&FrontendConfig {
+                socket_url: socket_url(&config, host, proto),
+            }).into_response()
+        });
+
+    // GET /... -> files of the built frontend
+    let assets = warp::get()
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(serve_asset);
+
+    frontend_config.or(assets).unify()
+}
+
+fn socket_url(config: &Config, host: Option<String>, proto: Option<String>) -> String {
+    if let Some(public_url) = &config.public_url {
+        let public_url = public_url.trim_end_matches('/');
+        return match public_url.strip_prefix("https://") {
+            Some(rest) => format!("wss://{}/socket", rest),
+            None => format!("ws://{}/socket", public_url.trim_start_matches("http://")),
+        };
+    }
+
+    // a TLS terminating proxy tells which scheme the browser used
+    let secure = config.tls_paths().is_some() || proto.is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+    let host = host.unwrap_or_else(|| config.socket_address().to_string());
+    format!("{}://{}/socket", if secure { "wss" } else { "ws" }, host)
+}
+
+async fn serve_asset(tail: Tail, if_none_match: Option<String>) -> Result<Response, Rejection> {
+    let path = match tail.as_str() {
+        "" => "index.html",
+        path => path,
+    };
+
+    match ASSETS.iter().find(|asset| asset.path == path) {
+        Some(asset) => Ok(asset_reply(asset, if_none_match.as_deref())),
+        None if ASSETS.is_empty() && path == "index.html" => Ok(warp::reply::html(NOT_BUILT_HTML).into_response()),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Content of an asset, or an empty 304 when the browser already has the current one
+fn asset_reply(asset: &Asset, if_none_match: Option<&str>) -> Response {
+    // the other files keep their name from one build to the next, browsers have to check they are still current
+    let cache_control = match asset.path.starts_with(IMMUTABLE_PREFIX) {
+        true => "public, max-age=31536000, immutable",
+        false => "no-cache",
+    };
+
+    let builder = warp::http::Response::builder()
+        .header(CACHE_CONTROL, cache_control)
+        .header(ETAG, asset.etag);
+
+    let response = match if_none_match.is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == asset.etag)) {
+        true => builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty()),
+        false => builder
+            .header(CONTENT_TYPE, mime_guess::from_path(asset.path).first_or_octet_stream().as_ref())
+            .body(Body::from(asset.content)),
+    };
+
+    response.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::HeaderValue;
+
+    use super::*;
+
+    const SCRIPT: Asset = Asset {
+        path: "assets/index-4f2a91c3.js",
+        content: b"console.log('nack')",
+        etag: "\"00000000000000a1\"",
+    };
+
+    const INDEX: Asset = Asset {
+        path: "index.html",
+        content: b"<!DOCTYPE html>",
+        etag: "\"00000000000000b2\"",
+    };
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a HeaderValue> {
+        response.headers().get(name)
+    }
+
+    #[test]
+    fn hashed_assets_are_immutable() {
+        let response = asset_reply(&SCRIPT, None);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "cache-control").unwrap(), "public, max-age=31536000, immutable");
+        assert_eq!(header(&response, "etag").unwrap(), SCRIPT.etag);
+        assert!(header(&response, "content-type").unwrap().to_str().unwrap().ends_with("/javascript"));
+    }
+
+    #[test]
+    fn index_is_revalidated() {
+        let response = asset_reply(&INDEX, None);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "cache-control").unwrap(), "no-cache");
+        assert_eq!(header(&response, "etag").unwrap(), INDEX.etag);
+        assert_eq!(header(&response, "content-type").unwrap(), "text/html");
+    }
+
+    #[tokio::test]
+    async fn current_etag_is_not_modified() {
+        for if_none_match in [INDEX.etag, "\"0000000000000000\", \"00000000000000b2\"", " \"00000000000000b2\" "] {
+            let response = asset_reply(&INDEX, Some(if_none_match));
+
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "If-None-Match: {}", if_none_match);
+            assert_eq!(header(&response, "cache-control").unwrap(), "no-cache");
+            assert_eq!(header(&response, "etag").unwrap(), INDEX.etag);
+            assert!(warp::hyper::body::to_bytes(response.into_body()).await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn stale_etag_gets_the_content() {
+        let response = asset_reply(&INDEX, Some("\"0000000000000000\""));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(warp::hyper::body::to_bytes(response.into_body()).await.unwrap(), INDEX.content);
+    }
+}
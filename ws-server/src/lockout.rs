@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Failed authentications of a peer address
+struct Failures {
+    count: u32,
+    /// Start of the window the failures are counted in
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Refuses the authentications of the peer addresses which used too many bad keys,
+/// failures are counted over a window as long as the lockout itself
+#[derive(Clone)]
+pub struct AuthLockout {
+    max_failures: u32,
+    duration: Duration,
+    failures: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+}
+
+impl AuthLockout {
+    /// A `max_failures` of 0 disables the lockout
+    pub fn new(max_failures: u32, duration: Duration) -> AuthLockout {
+        AuthLockout {
+            max_failures,
+            duration,
+            failures: Arc::default(),
+        }
+    }
+
+    pub fn is_locked(&self, address: IpAddr) -> bool {
+        self.failures.lock().unwrap()
+            .get(&address)
+            .and_then(|failures| failures.locked_until)
+            .is_some_and(|locked_until| locked_until > Instant::now())
+    }
+
+    /// Record a bad key sent from `address`, returning whether the address is now locked out
+    pub fn record_failure(&self, address: IpAddr) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        // forget the addresses which stopped trying
+        failures.retain(|_, failures| {
+            now.duration_since(failures.since) < self.duration || failures.locked_until.is_some_and(|until| until > now)
+        });
+
+        let entry = failures.entry(address).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        entry.count += 1;
+
+        if entry.count >= self.max_failures {
+            entry.locked_until = Some(now + self.duration);
+            entry.count = 0;
+            entry.since = now;
+            return true;
+        }
+        false
+    }
+
+    /// A successful authentication starts the count over
+    pub fn record_success(&self, address: IpAddr) {
+        self.failures.lock().unwrap().remove(&address);
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER_ADDRESS: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn locks_out_at_the_threshold() {
+        let lockout = AuthLockout::new(3, Duration::from_secs(60));
+
+        assert!(!lockout.record_failure(ADDRESS));
+        assert!(!lockout.record_failure(ADDRESS));
+        assert!(!lockout.is_locked(ADDRESS));
+
+        assert!(lockout.record_failure(ADDRESS));
+        assert!(lockout.is_locked(ADDRESS));
+        assert!(!lockout.is_locked(OTHER_ADDRESS));
+    }
+
+    #[test]
+    fn success_starts_the_count_over() {
+        let lockout = AuthLockout::new(2, Duration::from_secs(60));
+
+        assert!(!lockout.record_failure(ADDRESS));
+        lockout.record_success(ADDRESS);
+        assert!(!lockout.record_failure(ADDRESS));
+        assert!(!lockout.is_locked(ADDRESS));
+    }
+
+    #[test]
+    fn zero_disables_the_lockout() {
+        let lockout = AuthLockout::new(0, Duration::from_secs(60));
+
+        for _ in 0..100 {
+            assert!(!lockout.record_failure(ADDRESS));
+        }
+        assert!(!lockout.is_locked(ADDRESS));
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let lockout = AuthLockout::new(1, Duration::from_millis(50));
+
+        assert!(lockout.record_failure(ADDRESS));
+        assert!(lockout.is_locked(ADDRESS));
+
+        sleep(Duration::from_millis(60));
+        assert!(!lockout.is_locked(ADDRESS));
+    }
+
+    #[test]
+    fn failures_are_counted_over_a_window() {
+        let lockout = AuthLockout::new(2, Duration::from_millis(50));
+
+        assert!(!lockout.record_failure(ADDRESS));
+        sleep(Duration::from_millis(60));
+
+        // the first failure is out of the window, the address stopped trying
+        assert!(!lockout.record_failure(ADDRESS));
+        assert!(!lockout.is_locked(ADDRESS));
+        assert!(lockout.record_failure(ADDRESS));
+    }
+}
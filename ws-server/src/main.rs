@@ -1,14 +1,68 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::FutureExt;
 use warp::Filter;
 
+use audit::AuditLog;
+use config::{Command, Config};
+use store::Store;
 use socket::SocketHandler;
+use tls::CertResolver;
 
+mod api;
+mod audit;
+mod config;
+mod frontend;
+mod lockout;
+mod metrics;
+mod outbox;
+mod permissions;
+mod store;
 mod socket;
 mod requests_handler;
+mod sessions;
+mod tls;
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let socket_handler = SocketHandler::new();
+
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(command) = config.command {
+        run_command(command, &config);
+        return;
+    }
+
+    let store = match Store::open(&config.database_path) {
+        Ok(store) => store,
+        Err(e) => {
+            tracing::error!("Unable to open database {}: {}", config.database_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let audit = match AuditLog::open(&config.audit_log_path, store.clone()) {
+        Ok(audit) => audit,
+        Err(e) => {
+            tracing::error!("Unable to open audit log {}: {}", config.audit_log_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let socket_handler = SocketHandler::new(config.clone(), store, audit);
+    socket_handler.spawn_queue_expiry();
+
+    let requests_handler = socket_handler.requests_handler();
+    let api = api::routes(requests_handler.clone());
+    let frontend = frontend::routes(config.clone());
 
     // Turn our "state" into a new Filter...
     let socket_handler = warp::any().map(move || socket_handler.clone());
@@ -17,16 +71,107 @@ async fn main() {
     let socket = warp::path!("socket" / String)
         // The `ws()` filter will prepare Websocket handshake...
         .and(warp::ws())
+        .and(tls::remote_address())
         .and(socket_handler)
-        .map(|username: String, ws: warp::ws::Ws, socket_handler: SocketHandler| {
+        .map(|username: String, ws: warp::ws::Ws, address: Option<SocketAddr>, socket_handler: SocketHandler| {
             // This will call our function if the handshake succeeds.
-            ws.on_upgrade(move |socket| socket_handler.handle_connection(socket, username))
+            ws.on_upgrade(move |socket| socket_handler.handle_connection(socket, username, address))
         });
 
-    // GET / -> index html
-    // let index = warp::path::end().map(|| warp::reply::html(INDEX_HTML));
+    // /api/... and /metrics -> HTTP API sharing the state of the websocket connections, anything else -> admin page
+    let routes = socket.or(api).or(frontend);
+
+    // on SIGTERM or SIGINT the listener stops accepting connections while the open ones are drained
+    let shutdown = shutdown_signal().shared();
+    let drain = {
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown.await;
+            requests_handler.shutdown().await;
+        }
+    };
+
+    let (cert_path, key_path) = match config.tls_paths() {
+        Some(paths) => paths,
+        None => {
+            let (address, server) = match warp::serve(routes).try_bind_with_graceful_shutdown(config.socket_address(), shutdown) {
+                Ok(bound) => bound,
+                Err(e) => {
+                    tracing::error!("Unable to listen on {}: {}", config.socket_address(), e);
+                    std::process::exit(1);
+                }
+            };
+            tracing::info!("Listening on {}", address);
+            tokio::join!(server, drain);
+            return;
+        }
+    };
+
+    let resolver = match CertResolver::load(cert_path, key_path) {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            tracing::error!("Unable to load TLS certificate: {}", e);
+            std::process::exit(1);
+        }
+    };
+    resolver.spawn_reload();
+
+    let listener = match tokio::net::TcpListener::bind(config.socket_address()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Unable to listen on {}: {}", config.socket_address(), e);
+            std::process::exit(1);
+        }
+    };
+
+    tracing::info!("Listening on {} with TLS", config.socket_address());
+    let server = tls::serve(routes, listener, resolver.server_config(), shutdown);
+    tokio::join!(server, drain);
+}
+
+/// Resolves once the server is asked to stop, by SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                tracing::error!("Unable to listen for SIGTERM: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("Received SIGINT"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        tracing::info!("Received Ctrl-C");
+    }
+}
 
-    // let routes = index.or(chat);
+fn run_command(command: Command, config: &Config) {
+    match command {
+        Command::VerifyAudit => {
+            let store = match Store::open_read_only(&config.database_path) {
+                Ok(store) => store,
+                Err(e) => {
+                    tracing::error!("Unable to open database {}: {}", config.database_path.display(), e);
+                    std::process::exit(1);
+                }
+            };
 
-    warp::serve(socket).run(([0, 0, 0, 0], 3030)).await;
-}
\ No newline at end of file
+            match audit::verify(&config.audit_log_path, &store) {
+                Ok(entries) => tracing::info!("Audit log {} is intact, {} entries", config.audit_log_path.display(), entries),
+                Err(e) => {
+                    tracing::error!("Audit log {} failed verification: {}", config.audit_log_path.display(), e);
+                    std::process::exit(2);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use nack_protocol::JobState;
+
+/// Upper bounds in seconds of the job duration histogram buckets
+const JOB_DURATION_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Counters exposed on `/metrics` in the Prometheus text format, gauges are computed when scraped
+#[derive(Default)]
+pub struct Metrics {
+    messages_received: LabeledCounter,
+    messages_sent: LabeledCounter,
+    jobs_dispatched: AtomicU64,
+    jobs_finished: LabeledCounter,
+    job_duration: Histogram,
+    auth_failures: AtomicU64,
+    send_errors: AtomicU64,
+    idle_disconnects: AtomicU64,
+    auth_timeouts: AtomicU64,
+    messages_dropped: AtomicU64,
+    slow_consumer_disconnects: AtomicU64,
+}
+
+#[derive(Default)]
+struct LabeledCounter {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Default)]
+struct HistogramData {
+    /// Observations per bucket, not cumulated
+    buckets: [u64; JOB_DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Value of a gauge at the time of the scrape
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: usize,
+}
+
+impl Metrics {
+    /// Request received through a websocket, `action` being `invalid` for the ones which couldn't be parsed
+    pub fn message_received(&self, action: &str) {
+        self.messages_received.increment(action);
+    }
+
+    pub fn message_sent(&self, action: &str) {
+        self.messages_sent.increment(action);
+    }
+
+    pub fn job_dispatched(&self) {
+        self.jobs_dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn job_finished(&self, state: JobState) {
+        self.jobs_finished.increment(&state.to_string());
+    }
+
+    /// Time between the dispatch of a job and its result
+    pub fn job_duration(&self, seconds: f64) {
+        self.job_duration.observe(seconds);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn idle_disconnected(&self) {
+        self.idle_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_timed_out(&self) {
+        self.auth_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Message dropped from a full send queue to make room for a newer one
+    pub fn message_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_consumer_disconnected(&self) {
+        self.slow_consumer_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, gauges: &[Gauge]) -> String {
+        let mut out = String::new();
+
+        for gauge in gauges {
+            write_header(&mut out, gauge.name, gauge.help, "gauge");
+            writeln!(out, "{} {}", gauge.name, gauge.value).unwrap();
+        }
+
+        self.messages_received.render(&mut out, "nack_messages_received_total", "Requests received through websockets", "action");
+        self.messages_sent.render(&mut out, "nack_messages_sent_total", "Messages sent through websockets", "action");
+
+        write_header(&mut out, "nack_jobs_dispatched_total", "Jobs sent to their client", "counter");
+        writeln!(out, "nack_jobs_dispatched_total {}", self.jobs_dispatched.load(Ordering::Relaxed)).unwrap();
+
+        self.jobs_finished.render(&mut out, "nack_jobs_finished_total", "Jobs which reached a final state", "state");
+        self.job_duration.render(&mut out, "nack_job_duration_seconds", "Time between the dispatch of a job and its result");
+
+        write_header(&mut out, "nack_auth_failures_total", "Authentications with an unknown key", "counter");
+        writeln!(out, "nack_auth_failures_total {}", self.auth_failures.load(Ordering::Relaxed)).unwrap();
+
+        write_header(&mut out, "nack_send_errors_total", "Messages which couldn't be sent to a connection", "counter");
+        writeln!(out, "nack_send_errors_total {}", self.send_errors.load(Ordering::Relaxed)).unwrap();
+
+        write_header(&mut out, "nack_idle_disconnects_total", "Connections closed after staying silent", "counter");
+        writeln!(out, "nack_idle_disconnects_total {}", self.idle_disconnects.load(Ordering::Relaxed)).unwrap();
+
+        write_header(&mut out, "nack_auth_timeouts_total", "Connections closed without having authenticated in time", "counter");
+        writeln!(out, "nack_auth_timeouts_total {}", self.auth_timeouts.load(Ordering::Relaxed)).unwrap();
+
+        write_header(&mut out, "nack_messages_dropped_total", "Messages dropped from a full send queue", "counter");
+        writeln!(out, "nack_messages_dropped_total {}", self.messages_dropped.load(Ordering::Relaxed)).unwrap();
+
+        write_header(&mut out, "nack_slow_consumer_disconnects_total", "Connections closed for not reading their messages fast enough", "counter");
+        writeln!(out, "nack_slow_consumer_disconnects_total {}", self.slow_consumer_disconnects.load(Ordering::Relaxed)).unwrap();
+
+        out
+    }
+}
+
+impl LabeledCounter {
+    fn increment(&self, label: &str) {
+        *self.values.lock().unwrap().entry(label.to_string()).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        write_header(out, name, help, "counter");
+        for (value, count) in self.values.lock().unwrap().iter() {
+            writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count).unwrap();
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        if let Some(bucket) = JOB_DURATION_BUCKETS.iter().position(|bound| value <= *bound) {
+            data.buckets[bucket] += 1;
+        }
+        data.count += 1;
+        data.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let data = self.data.lock().unwrap();
+        write_header(out, name, help, "histogram");
+
+        let mut cumulated = 0;
+        for (bound, count) in JOB_DURATION_BUCKETS.iter().zip(data.buckets.iter()) {
+            cumulated += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulated).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count).unwrap();
+        writeln!(out, "{}_sum {}", name, data.sum).unwrap();
+        writeln!(out, "{}_count {}", name, data.count).unwrap();
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.message_received("run_request");
+        metrics.message_received("run_request");
+        metrics.message_received("invalid");
+        metrics.job_finished(JobState::Succeeded);
+        // exact binary fractions, for the sum to be rendered as is
+        metrics.job_duration(0.0625);
+        metrics.job_duration(0.25);
+        metrics.job_duration(0.25);
+        metrics.job_duration(400.0);
+
+        let out = metrics.render(&[Gauge { name: "nack_clients", help: "Connected clients", value: 3 }]);
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(&lines[..3], ["# HELP nack_clients Connected clients", "# TYPE nack_clients gauge", "nack_clients 3"]);
+        assert!(out.contains(concat!(
+            "# TYPE nack_messages_received_total counter\n",
+            "nack_messages_received_total{action=\"invalid\"} 1\n",
+            "nack_messages_received_total{action=\"run_request\"} 2\n",
+            "# HELP nack_messages_sent_total",
+        )));
+        assert!(out.contains("nack_jobs_finished_total{state=\"succeeded\"} 1\n"));
+        assert!(out.contains(concat!(
+            "# TYPE nack_job_duration_seconds histogram\n",
+            "nack_job_duration_seconds_bucket{le=\"0.05\"} 0\n",
+            "nack_job_duration_seconds_bucket{le=\"0.1\"} 1\n",
+            "nack_job_duration_seconds_bucket{le=\"0.25\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"0.5\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"1\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"2.5\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"5\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"10\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"30\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"60\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"300\"} 3\n",
+            "nack_job_duration_seconds_bucket{le=\"+Inf\"} 4\n",
+            "nack_job_duration_seconds_sum 400.5625\n",
+            "nack_job_duration_seconds_count 4\n",
+        )));
+        assert!(out.ends_with("nack_slow_consumer_disconnects_total 0\n"));
+    }
+
+    #[test]
+    fn empty_counters_only_have_a_header() {
+        let out = Metrics::default().render(&[]);
+        assert!(out.starts_with(concat!(
+            "# HELP nack_messages_received_total Requests received through websockets\n",
+            "# TYPE nack_messages_received_total counter\n",
+            "# HELP nack_messages_sent_total",
+        )));
+        assert!(out.contains("nack_job_duration_seconds_bucket{le=\"+Inf\"} 0\nnack_job_duration_seconds_sum 0\nnack_job_duration_seconds_count 0\n"));
+    }
+}
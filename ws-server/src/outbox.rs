@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+use crate::config::SlowConsumerPolicy;
+
+/// Messages waiting to be written to a websocket, bounded so that a slow or stuck
+/// connection can't make the server grow memory without bound
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    queue: Mutex<Queue>,
+    /// Notified when a message is queued or the outbox is closed
+    changed: Notify,
+    /// Notified when the queue overflows with the `Disconnect` policy
+    overflow: Notify,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Outgoing>,
+    closed: bool,
+    overflowed: bool,
+}
+
+/// Message waiting in a queue
+pub enum Outgoing {
+    /// Text serialized once and shared by the queues of every recipient
+    Text(Arc<str>),
+    Message(Message),
+}
+
+impl From<Message> for Outgoing {
+    fn from(message: Message) -> Outgoing {
+        Outgoing::Message(message)
+    }
+}
+
+/// Outcome of queueing a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    Queued,
+    /// The queue was full, the oldest message was dropped to make room
+    DroppedOldest,
+}
+
+/// Why a message couldn't be queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxError {
+    /// The connection is closing
+    Closed,
+    /// The queue was full, the outbox is now closed and the connection dropped
+    Overflow,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Outbox {
+        Outbox {
+            inner: Arc::new(Inner {
+                queue: Mutex::default(),
+                changed: Notify::new(),
+                overflow: Notify::new(),
+                capacity,
+                policy,
+            }),
+        }
+    }
+
+    pub fn push(&self, message: impl Into<Outgoing>) -> Result<Queued, OutboxError> {
+        let mut queue = self.inner.queue.lock().unwrap();
+        if queue.closed {
+            return Err(OutboxError::Closed);
+        }
+
+        let mut queued = Queued::Queued;
+        if queue.messages.len() >= self.inner.capacity {
+            match self.inner.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    queue.messages.pop_front();
+                    queued = Queued::DroppedOldest;
+                }
+                SlowConsumerPolicy::Disconnect => {
+                    queue.closed = true;
+                    queue.overflowed = true;
+                    queue.messages.clear();
+                    drop(queue);
+                    self.inner.changed.notify_one();
+                    self.inner.overflow.notify_waiters();
+                    return Err(OutboxError::Overflow);
+                }
+            }
+        }
+
+        queue.messages.push_back(message.into());
+        drop(queue);
+        self.inner.changed.notify_one();
+        Ok(queued)
+    }
+
+    /// Next message to write, `None` once the outbox is closed and drained
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            // a permit is stored by `notify_one` when nobody waits, so a push in between isn't missed
+            let changed = self.inner.changed.notified();
+
+            {
+                let mut queue = self.inner.queue.lock().unwrap();
+                match queue.messages.pop_front() {
+                    Some(Outgoing::Text(text)) => return Some(Message::text(&*text)),
+                    Some(Outgoing::Message(message)) => return Some(message),
+                    None => {}
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Resolves once the queue overflowed, the writer may be stuck on a peer which stopped reading
+    pub async fn overflowed(&self) {
+        loop {
+            let overflow = self.inner.overflow.notified();
+            if self.inner.queue.lock().unwrap().overflowed {
+                return;
+            }
+            overflow.await;
+        }
+    }
+
+    /// Stop accepting messages, the pending ones are still written
+    pub fn close(&self) {
+        self.inner.queue.lock().unwrap().closed = true;
+        self.inner.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn texts(outbox: &Outbox) -> Vec<String> {
+        let queue = outbox.inner.queue.lock().unwrap();
+        queue.messages
+            .iter()
+            .map(|message| match message {
+                Outgoing::Text(text) => text.to_string(),
+                Outgoing::Message(message) => message.to_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_messages() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+        assert_eq!(outbox.push(Message::text("1")), Ok(Queued::Queued));
+        assert_eq!(outbox.push(Message::text("2")), Ok(Queued::Queued));
+        assert_eq!(outbox.push(Message::text("3")), Ok(Queued::DroppedOldest));
+        assert_eq!(texts(&outbox), ["2", "3"]);
+
+        // the connection stays open
+        assert_eq!(outbox.pop().await.unwrap().to_str(), Ok("2"));
+        assert_eq!(outbox.push(Outgoing::Text(Arc::from("4"))), Ok(Queued::Queued));
+        assert_eq!(texts(&outbox), ["3", "4"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_on_overflow() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::Disconnect);
+        assert_eq!(outbox.push(Message::text("1")), Ok(Queued::Queued));
+        assert_eq!(outbox.push(Message::text("2")), Ok(Queued::Queued));
+
+        let overflowed = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.overflowed().await }
+        });
+        assert_eq!(outbox.push(Message::text("3")), Err(OutboxError::Overflow));
+        tokio::time::timeout(Duration::from_secs(1), overflowed).await.unwrap().unwrap();
+
+        // the pending messages are dropped along with the connection
+        assert_eq!(outbox.push(Message::text("4")), Err(OutboxError::Closed));
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn close_drains_pending_messages() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+        outbox.push(Message::text("1")).unwrap();
+        outbox.close();
+
+        assert_eq!(outbox.push(Message::text("2")), Err(OutboxError::Closed));
+        assert_eq!(outbox.pop().await.unwrap().to_str(), Ok("1"));
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_a_push() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+        let popped = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.pop().await }
+        });
+
+        tokio::task::yield_now().await;
+        outbox.push(Message::text("1")).unwrap();
+        let popped = tokio::time::timeout(Duration::from_secs(1), popped).await.unwrap().unwrap();
+        assert_eq!(popped.unwrap().to_str(), Ok("1"));
+    }
+}
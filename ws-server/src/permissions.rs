@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use nack_protocol::LabelExpression;
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+const ANY_MODULE: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    /// Can only look at clients and jobs
+    Viewer,
+    /// Can run the modules allowed to the role on the clients allowed to the role
+    Operator,
+    /// Can do anything, including editing client labels
+    Superuser,
+}
+
+/// What an admin holding a role can run, as written in the configuration file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RolePermissionsConfig {
+    /// Modules the role can run, `*` allows every module
+    pub modules: Option<Vec<String>>,
+    /// Label expression the targeted clients must match, every client is allowed when omitted
+    pub labels: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RolePermissions {
+    modules: Vec<String>,
+    labels: Option<LabelExpression>,
+}
+
+/// Named admin account, authenticated by its key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminAccount {
+    pub name: String,
+    pub key: String,
+    pub role: Role,
+}
+
+impl Role {
+    pub fn can_edit_labels(&self) -> bool {
+        *self == Role::Superuser
+    }
+
+    fn default_modules(&self) -> Vec<String> {
+        match self {
+            Role::Viewer => vec![],
+            Role::Operator => vec!["open_url".to_string(), "play_url".to_string()],
+            Role::Superuser => vec![ANY_MODULE.to_string()],
+        }
+    }
+}
+
+impl RolePermissions {
+    pub fn can_run(&self, module: &str) -> bool {
+        self.modules.iter().any(|allowed| allowed == ANY_MODULE || allowed == module)
+    }
+
+    /// Whether every client can be targeted, whatever its labels
+    pub fn can_target_any(&self) -> bool {
+        self.labels.is_none()
+    }
+
+    pub fn can_target(&self, labels: &[String]) -> bool {
+        match &self.labels {
+            Some(expression) => expression.matches(labels),
+            None => true,
+        }
+    }
+}
+
+/// Resolve the permissions of every role, falling back to the defaults for the unconfigured ones
+pub fn resolve_roles(configured: HashMap<Role, RolePermissionsConfig>) -> Result<HashMap<Role, RolePermissions>, String> {
+    let mut roles = HashMap::new();
+
+    for role in [Role::Viewer, Role::Operator, Role::Superuser] {
+        let config = configured.get(&role).cloned().unwrap_or_default();
+
+        let labels = match config.labels {
+            Some(labels) => Some(
+                LabelExpression::from_str(&labels).map_err(|e| format!("role {}: {}", role, e))?
+            ),
+            None => None,
+        };
+
+        roles.insert(role, RolePermissions {
+            modules: config.modules.unwrap_or_else(|| role.default_modules()),
+            labels,
+        });
+    }
+
+    Ok(roles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    fn defaults() -> HashMap<Role, RolePermissions> {
+        resolve_roles(HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn viewer_cannot_run_anything() {
+        let viewer = &defaults()[&Role::Viewer];
+
+        for module in ["exec", "open_url", "play_url", ANY_MODULE] {
+            assert!(!viewer.can_run(module), "viewer can run {}", module);
+        }
+        assert!(!Role::Viewer.can_edit_labels());
+    }
+
+    #[test]
+    fn operator_defaults_exclude_exec() {
+        let operator = &defaults()[&Role::Operator];
+
+        assert!(operator.can_run("open_url"));
+        assert!(operator.can_run("play_url"));
+        assert!(!operator.can_run("exec"));
+        assert!(operator.can_target_any());
+        assert!(!Role::Operator.can_edit_labels());
+    }
+
+    #[test]
+    fn superuser_wildcard_allows_everything() {
+        let superuser = &defaults()[&Role::Superuser];
+
+        for module in ["exec", "open_url", "play_url", "added_later"] {
+            assert!(superuser.can_run(module), "superuser can't run {}", module);
+        }
+        assert!(superuser.can_target(&labels(&[])));
+        assert!(Role::Superuser.can_edit_labels());
+    }
+
+    #[test]
+    fn configured_roles_replace_the_defaults() {
+        let roles = resolve_roles(HashMap::from([
+            (Role::Operator, RolePermissionsConfig {
+                modules: Some(vec!["exec".to_string()]),
+                labels: None,
+            }),
+        ])).unwrap();
+
+        assert!(roles[&Role::Operator].can_run("exec"));
+        assert!(!roles[&Role::Operator].can_run("open_url"));
+
+        // the unconfigured roles keep their defaults
+        assert!(!roles[&Role::Viewer].can_run("open_url"));
+        assert!(roles[&Role::Superuser].can_run("exec"));
+    }
+
+    #[test]
+    fn labels_restrict_the_targets() {
+        let roles = resolve_roles(HashMap::from([
+            (Role::Operator, RolePermissionsConfig {
+                modules: None,
+                labels: Some("lab&!server".to_string()),
+            }),
+        ])).unwrap();
+        let operator = &roles[&Role::Operator];
+
+        assert!(!operator.can_target_any());
+        assert!(operator.can_target(&labels(&["lab"])));
+        assert!(!operator.can_target(&labels(&["lab", "server"])));
+        assert!(!operator.can_target(&labels(&["office"])));
+        assert!(!operator.can_target(&labels(&[])));
+
+        // the modules still default
+        assert!(operator.can_run("open_url"));
+        assert!(!operator.can_run("exec"));
+    }
+
+    #[test]
+    fn invalid_label_expression_is_rejected() {
+        let error = resolve_roles(HashMap::from([
+            (Role::Viewer, RolePermissionsConfig {
+                modules: None,
+                labels: Some("lab&&".to_string()),
+            }),
+        ])).unwrap_err();
+
+        assert!(error.starts_with("role viewer: "), "{}", error);
+    }
+}
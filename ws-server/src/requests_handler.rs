@@ -1,245 +1,1458 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use nack_protocol::{is_valid_label, AuthRequestBody, AuthenticatedBody, ClientInfo, ClientsUpdateBody, ErrorBody, ErrorCode, GetJobRequestBody, JobFilter, JobListBody, JobRecord, JobState, JobSubscriptionBody, JobSummary, LabelExpression, RejectedTarget, Request, RequestAction, Response, RunAcceptedBody, RunBody, RunRequestBody, RunResponseBody, RunStartedBody, Selector, SetLabelsRequestBody, ShuttingDownBody};
+use nanoid::nanoid;
 use serde_json::Value;
-use strum_macros::{Display, EnumString};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{Notify, RwLock};
 use warp::ws::Message;
 
-type Users = Arc<RwLock<HashMap<String, mpsc::UnboundedSender<Message>>>>;
-type Usernames = Arc<RwLock<Vec<String>>>;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config::{Config, DuplicateClientPolicy};
+use crate::lockout::AuthLockout;
+use crate::metrics::{Gauge, Metrics};
+use crate::outbox::{Outbox, OutboxError, Outgoing, Queued};
+use crate::sessions::{AdminSession, ClientSession, Principal, Session, SessionRegistry, SessionState};
+use crate::store::{now, Store};
 
+type Subscribers = Arc<RwLock<HashMap<String, Vec<String>>>>;
 
-#[derive(Debug, Display, EnumString)]
-#[strum(serialize_all = "snake_case")]
-pub(crate) enum RequestActionTypes {
-    AuthRequest,
-    GetClientsRequest,
-    RunRequest,
-    RunResponse,
-}
-
-
-#[derive(Debug, Serialize, Deserialize)]
-struct BasicRequest {
-    action: String,
-    data: Value,
-}
+const MAX_MACHINE_ID_LENGTH: usize = 128;
+const MAX_INVENTORY_LENGTH: usize = 4096;
+const MAX_CAPABILITIES_LENGTH: usize = 16384;
 
+/// Connection name recorded in the audit log for HTTP API requests
+const API_CONNECTION: &str = "api";
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AuthRequestBody {
-    app_key: String,
-}
+/// Time left to the connections to close once the grace period of a shutdown is over
+const SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Serialize, Deserialize)]
-struct BasicRequestResponse {
-    action: String,
-    data: Value,
+/// Why a request failed, sent back as an error message or as an HTTP status
+#[derive(Debug)]
+pub(crate) struct RequestError {
+    pub code: ErrorCode,
+    pub message: String,
 }
 
-impl BasicRequestResponse {
-    fn new(action: String, data: Value) -> BasicRequestResponse {
-        BasicRequestResponse {
-            action,
-            data,
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> RequestError {
+        RequestError {
+            code,
+            message: message.into(),
         }
     }
-
-    fn to_json_string(&self) -> String {
-        serde_json::to_string(&self).unwrap()
-    }
 }
 
-
-#[derive(Debug, Serialize, Deserialize)]
-struct RunRequestBody {
-    target: String,
-    module: String,
-    params: Value,
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
 }
 
-
 #[derive(Clone)]
 pub(crate) struct RequestsHandler {
-    pub connected_users: Users,
-    logged_in_clients: Usernames,
-    logged_in_admins: Usernames,
+    sessions: Arc<SessionRegistry>,
+    store: Store,
+    job_subscribers: Subscribers,
+    config: Arc<Config>,
+    audit: AuditLog,
+    /// Notified whenever a job finishes, for the HTTP requests waiting for one
+    job_updates: Arc<Notify>,
+    metrics: Arc<Metrics>,
+    lockout: AuthLockout,
+    /// Set once the server was asked to stop, no job is created afterwards
+    shutting_down: Arc<AtomicBool>,
 }
 
 impl RequestsHandler {
-    pub fn new() -> RequestsHandler {
+    pub fn new(config: Arc<Config>, store: Store, audit: AuditLog, metrics: Arc<Metrics>) -> RequestsHandler {
         RequestsHandler {
-            connected_users: Users::default(),
-            logged_in_clients: Usernames::default(),
-            logged_in_admins: Usernames::default(),
+            sessions: Arc::new(SessionRegistry::new()),
+            store,
+            job_subscribers: Subscribers::default(),
+            audit,
+            job_updates: Arc::default(),
+            metrics,
+            lockout: AuthLockout::new(config.max_auth_failures, Duration::from_secs(config.auth_lockout)),
+            shutting_down: Arc::default(),
+            config,
         }
     }
 
-    pub async fn handle_new_socket_connection(&self, username: &String, tx: &mpsc::UnboundedSender<Message>) {
-        tracing::info!("{} connected", username);
-        self.connected_users.write().await.insert(username.clone(), tx.clone());
+    /// Metrics in the Prometheus text format, along with the current number of connections
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&[
+            Gauge {
+                name: "nack_connected_sockets",
+                help: "Open websocket connections",
+                value: self.sessions.len(),
+            },
+            Gauge {
+                name: "nack_logged_in_clients",
+                help: "Connections authenticated as a client",
+                value: self.sessions.client_count(),
+            },
+            Gauge {
+                name: "nack_logged_in_admins",
+                help: "Connections authenticated as an admin",
+                value: self.sessions.admin_count(),
+            },
+        ])
     }
 
-    pub async fn handle_disconnected_socket(&self, username: &String) {
-        tracing::info!("{} disconnected", username);
-        self.connected_users.write().await.remove(username);
+    pub async fn handle_new_socket_connection(&self, username: &str, name: &str, address: Option<IpAddr>, outbox: &Outbox) {
+        match address {
+            Some(address) => tracing::info!("{} connected from {}", username, address),
+            None => tracing::info!("{} connected", username),
+        }
+        self.sessions.insert(username, Session::new(name, address, outbox.clone()));
+    }
+
+    /// Whether the connection authenticated, it is closed by its socket handler otherwise once `auth_timeout` is reached
+    pub fn is_authenticated(&self, username: &str) -> bool {
+        self.sessions
+            .get(username, |session| !matches!(session.state, SessionState::Connected))
+            .unwrap_or(false)
+    }
 
-        // if user is logged in, remove him from the logged in users list
-        if self.logged_in_clients.read().await.contains(username) {
-            self.logged_in_clients.write().await.retain(|x| x != username);
+    /// Ask a connection to close, its disconnection is then handled by its own task
+    fn close_connection(&self, username: &str) {
+        if let Some(outbox) = self.sessions.outbox(username) {
+            let _ = outbox.push(Message::close());
+        }
+    }
 
+    pub async fn handle_disconnected_socket(&self, username: &str) {
+        tracing::info!("{} disconnected", username);
+
+        // the session of a client is kept when it was already replaced by a newer connection
+        if let Some(client_id) = self.sessions.remove(username) {
             // propagate the new list of logged in users to all the admins
             self.send_clients_updates().await;
+
+            // the jobs the client was working on will never be answered
+            self.handle_lost_jobs(&client_id).await;
         }
+    }
+
+    /// Stop taking jobs and tell every connection the server is going away, give the clients
+    /// `shutdown_grace_period` to answer the jobs in flight, then mark the others as lost and close the connections
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
 
-        // if admin is logged in, remove him from the logged in admin list
-        if self.logged_in_admins.read().await.contains(username) {
-            self.logged_in_admins.write().await.retain(|x| x != username);
+        let grace_period = Duration::from_secs(self.config.shutdown_grace_period);
+        tracing::info!("Shutting down, waiting up to {} seconds for the jobs in flight", grace_period.as_secs());
+        self.send_messages(
+            &self.sessions.usernames(),
+            &Response::ShuttingDown(ShuttingDownBody {
+                grace_period: grace_period.as_secs(),
+            }),
+        ).await;
+
+        let deadline = tokio::time::Instant::now() + grace_period;
+        loop {
+            // registered before counting the jobs so that a response in between isn't missed
+            let updated = self.job_updates.notified();
+            tokio::pin!(updated);
+            updated.as_mut().enable();
+
+            match self.store.count_in_flight() {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("Unable to count the jobs in flight: {}", e);
+                    break;
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                break;
+            }
+        }
+
+        // the responses which didn't come in time will never be received
+        match self.store.mark_all_in_flight_lost() {
+            Ok(job_ids) => {
+                for job_id in job_ids {
+                    tracing::info!("Job {} lost", job_id);
+                    self.metrics.job_finished(JobState::Lost);
+                    self.audit.record(AuditEvent::JobFinished {
+                        job_id: &job_id,
+                        state: JobState::Lost,
+                        output: None,
+                    });
+                    self.send_job_update(&job_id, true).await;
+                }
+            }
+            Err(e) => tracing::error!("Unable to update the jobs in flight: {}", e),
+        }
+
+        // the writers flush what is left in the queues before closing
+        for (_, outbox) in self.sessions.outboxes(&self.sessions.usernames()) {
+            let _ = outbox.push(Message::close());
+            outbox.close();
+        }
+
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_CLOSE_TIMEOUT;
+        while self.sessions.len() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        self.audit.flush().await;
+        tracing::info!("Shutdown complete, {} connections left open", self.sessions.len());
     }
 
-    pub async fn handle_request(&self, message: Message, username: &String) {
+    pub async fn handle_request(&self, message: Message, username: &str) {
         tracing::debug!("Received message: {:?}", message);
 
-        let parsed_message: BasicRequest = match serde_json::from_str(message.to_str().unwrap()) {
+        let (request_id, action, parsed_message) = parse_request(message.to_str().unwrap_or_default());
+        let parsed_message = match parsed_message {
             Ok(parsed_message) => parsed_message,
-            Err(_) => return, // If the message is not a valid JSON, ignore it
+            Err(e) => {
+                tracing::error!("Invalid request: {}", e);
+                self.metrics.message_received("invalid");
+                self.send_request_error(username, action.as_deref(), request_id, e).await;
+                return;
+            }
         };
 
+        let action = parsed_message.to_string();
+        self.metrics.message_received(&action);
+
         tracing::debug!("Parsed message: {:?}", parsed_message);
-        tracing::info!("New action request: {:?}", parsed_message.action);
+        tracing::info!("New action request: {}", parsed_message);
 
         // dispatch action to the corresponding function
-        match RequestActionTypes::from_str(&*parsed_message.action) {
-            Ok(RequestActionTypes::AuthRequest) => self.handle_auth_request(parsed_message.data, username).await,
-            Ok(RequestActionTypes::GetClientsRequest) => self.handle_get_clients_request(username).await,
-            Ok(RequestActionTypes::RunRequest) => self.handle_run_request(parsed_message.data, username).await,
-            Ok(RequestActionTypes::RunResponse) => self.handle_run_response(parsed_message.data).await,
-            Err(_) => tracing::error!("Invalid action {:?}", parsed_message.action),
+        let result = match parsed_message {
+            Request::AuthRequest(data) => self.handle_auth_request(data, username).await,
+            Request::GetClientsRequest => self.handle_get_clients_request(username).await,
+            Request::RunRequest(data) => self.handle_run_request(data, username).await,
+            Request::RunStarted(data) => self.handle_run_started(data, username).await,
+            Request::RunResponse(data) => self.handle_run_response(data, username).await,
+            Request::SubscribeJobRequest(data) => self.handle_subscribe_job_request(data, username).await,
+            Request::ListJobsRequest(data) => self.handle_list_jobs_request(data, username).await,
+            Request::GetJobRequest(data) => self.handle_get_job_request(data, username).await,
+            Request::SetLabelsRequest(data) => self.handle_set_labels_request(data, username).await,
+        };
+
+        // every failure is answered, so that the sender knows why nothing happened
+        if let Err(e) = result {
+            tracing::error!("{} of {} failed: {}", action, username, e);
+            let close = e.code == ErrorCode::LockedOut;
+            self.send_request_error(username, Some(&action), request_id, e).await;
+
+            if close {
+                self.close_connection(username);
+            }
+        }
+    }
+
+    /// A connection answered a ping, `latency_ms` after it was sent
+    pub async fn handle_pong(&self, username: &str, latency_ms: u64) {
+        let client_id = self.sessions.update(username, |session| match &mut session.state {
+            SessionState::Authenticated(Principal::Client(client)) => {
+                client.last_seen = now();
+                client.latency_ms = Some(latency_ms);
+                Some(client.id.clone())
+            }
+            _ => None,
+        });
+
+        let client_id = match client_id.flatten() {
+            Some(client_id) => client_id,
+            None => return,
+        };
+
+        if let Err(e) = self.store.touch_client(&client_id) {
+            tracing::error!("Unable to update last seen time of {}: {}", client_id, e);
         }
     }
 
     async fn send_clients_updates(&self) {
         self.send_messages(
-            &self.logged_in_admins.read().await.clone(),
-            &BasicRequestResponse::new(
-                "clients_update".to_string(),
-                serde_json::json!({
-                            "connected_clients": self.logged_in_clients.read().await.clone(),
-                        }),
-            ).to_json_string(),
+            &self.sessions.admin_usernames(None),
+            &Response::ClientsUpdate(ClientsUpdateBody {
+                connected_clients: self.client_infos(),
+            }),
         ).await;
     }
 
-    async fn get_tx(&self, username: &String) -> Option<mpsc::UnboundedSender<Message>> {
-        match self.connected_users.read().await.get(username) {
-            Some(tx) => Some(tx.clone()),
+    pub fn client_infos(&self) -> Vec<ClientInfo> {
+        self.sessions.map_clients(ClientSession::info)
+    }
+
+    /// Send a message to the connection of a logged in client
+    async fn send_to_client(&self, client_id: &str, response: &Response) {
+        let username = match self.sessions.client_username(client_id) {
+            Some(username) => username,
             None => {
-                tracing::info!("{} is not connected", username);
-                None
+                tracing::info!("{} is not logged in", client_id);
+                return;
             }
+        };
+
+        self.send_messages(&[username], response).await;
+    }
+
+    /// Serialize a message once and queue it for every connection
+    async fn send_messages(&self, usernames: &[String], response: &Response) {
+        let message: Arc<str> = Arc::from(response.to_json_string());
+        let action = response.to_string();
+
+        let outboxes = self.sessions.outboxes(usernames);
+        if outboxes.len() < usernames.len() {
+            tracing::info!("{} of the {} recipients of {} are not connected", usernames.len() - outboxes.len(), usernames.len(), action);
+        }
+
+        for (username, outbox) in outboxes {
+            // the connection may be closing, its disconnection is handled by its own task
+            match outbox.push(Outgoing::Text(message.clone())) {
+                Ok(Queued::Queued) => {}
+                Ok(Queued::DroppedOldest) => {
+                    tracing::debug!("Send queue of {} is full, dropped its oldest message", username);
+                    self.metrics.message_dropped();
+                }
+                Err(OutboxError::Closed) => {
+                    tracing::error!("Unable to send {} to {}", action, username);
+                    self.metrics.send_failed();
+                    continue;
+                }
+                Err(OutboxError::Overflow) => {
+                    tracing::error!("Send queue of {} is full, closing the connection", username);
+                    self.metrics.slow_consumer_disconnected();
+                    continue;
+                }
+            }
+
+            self.metrics.message_sent(&action);
         }
     }
 
-    async fn send_messages(&self, usernames: &Vec<String>, message: &String) {
-        for username in usernames {
-            let tx = match self.get_tx(username).await {
-                Some(tx) => tx,
-                None => continue,
+    async fn handle_get_clients_request(&self, username: &str) -> Result<(), RequestError> {
+        self.require_admin(username, "get_clients_request").await?;
+
+        self.send_messages(
+            &[username.to_string()],
+            &Response::ClientsUpdate(ClientsUpdateBody {
+                connected_clients: self.client_infos(),
+            }),
+        ).await;
+        Ok(())
+    }
+
+    async fn handle_auth_request(&self, data: AuthRequestBody, username: &str) -> Result<(), RequestError> {
+        let session = self.sessions.get(username, |session| {
+            let authenticated = match &session.state {
+                SessionState::Connected => None,
+                SessionState::Authenticated(principal) => Some(principal.to_string()),
+                SessionState::Replaced => Some("a replaced client".to_string()),
             };
+            (authenticated, session.address)
+        });
+        let (authenticated, address) = match session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        // a connection is either a client or an admin, for good
+        if let Some(principal) = authenticated {
+            return Err(RequestError::new(ErrorCode::AlreadyAuthenticated, format!("Already authenticated as {}", principal)));
+        }
+
+        if address.is_some_and(|address| self.lockout.is_locked(address)) {
+            return Err(self.locked_out_error());
+        }
+
+        if self.config.is_client_key(&data.app_key) {
+            // clients without a machine ID are only known for the lifetime of their connection
+            let client_id = data.machine_id.clone().unwrap_or_else(|| username.to_string());
+            self.handle_client_login(client_id, data, username).await?;
+        } else if let Some(admin) = self.config.find_admin(&data.app_key) {
+            tracing::info!("{} logged in as admin {} ({})", username, admin.name, admin.role);
+            self.audit.record(AuditEvent::AdminLogin {
+                connection: username,
+                admin: &admin.name,
+                role: admin.role,
+            });
+            self.sessions.login_admin(username, AdminSession {
+                name: admin.name.clone(),
+                role: admin.role,
+            });
+            self.send_authenticated(username, &admin.name).await;
+        } else {
+            return Err(self.handle_auth_failure(username, address).await);
+        }
+
+        if let Some(address) = address {
+            self.lockout.record_success(address);
+        }
+        Ok(())
+    }
 
-            tx.send(Message::text(message.clone())).unwrap();
+    /// Let a connection know it is logged in, before any job is dispatched to it
+    async fn send_authenticated(&self, username: &str, name: &str) {
+        self.send_messages(
+            &[username.to_string()],
+            &Response::Authenticated(AuthenticatedBody { name: name.to_string() }),
+        ).await;
+    }
+
+    /// Count a bad key, locking out its sender once it sent too many of them
+    async fn handle_auth_failure(&self, username: &str, address: Option<IpAddr>) -> RequestError {
+        self.audit.record(AuditEvent::AuthFailed { connection: username });
+        self.metrics.auth_failed();
+
+        let locked = match address {
+            Some(address) => self.lockout.record_failure(address),
+            // without the peer address, each connection is limited on its own
+            None => self.sessions
+                .update(username, |session| {
+                    session.auth_failures += 1;
+                    self.config.max_auth_failures > 0 && session.auth_failures >= self.config.max_auth_failures
+                })
+                .unwrap_or(false),
+        };
+
+        if !locked {
+            return RequestError::new(ErrorCode::Unauthorized, "Invalid app key");
         }
+
+        tracing::info!("{} locked out after {} failed authentications", username, self.config.max_auth_failures);
+        self.audit.record(AuditEvent::LockedOut {
+            connection: username,
+            address,
+        });
+        self.locked_out_error()
     }
 
-    async fn handle_get_clients_request(&self, username: &String) {
-        if !self.logged_in_admins.read().await.contains(username) {
-            tracing::info!("{} is not an admin", username);
-            return;
+    fn locked_out_error(&self) -> RequestError {
+        RequestError::new(
+            ErrorCode::LockedOut,
+            format!("Too many failed authentications, retry in {} seconds", self.lockout.duration().as_secs()),
+        )
+    }
+
+    async fn handle_client_login(&self, client_id: String, data: AuthRequestBody, username: &str) -> Result<(), RequestError> {
+        if client_id.is_empty()
+            || client_id.len() > MAX_MACHINE_ID_LENGTH
+            || !client_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(RequestError::new(ErrorCode::InvalidBody, format!("Invalid machine ID {:?}", client_id)));
         }
 
-        let logged_in_clients = self.logged_in_clients.read().await.clone();
+        let name = match self.sessions.get(username, |session| session.name.clone()) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
 
+        let reported_labels: Vec<String> = data.labels
+            .into_iter()
+            .filter(|label| {
+                let valid = is_valid_label(label);
+                if !valid {
+                    tracing::error!("Ignoring invalid label {:?} of {}", label, client_id);
+                }
+                valid
+            })
+            .collect();
+
+        // the inventory is only informative, an oversized one is dropped rather than refusing the client
+        let inventory = data.inventory.filter(|inventory| {
+            let valid = serde_json::to_string(inventory).unwrap().len() <= MAX_INVENTORY_LENGTH;
+            if !valid {
+                tracing::error!("Ignoring oversized inventory of {}", client_id);
+            }
+            valid
+        });
+
+        // without capabilities run requests are forwarded unchecked, as for clients which don't advertise them
+        let capabilities = data.capabilities.filter(|capabilities| {
+            let valid = serde_json::to_string(capabilities).unwrap().len() <= MAX_CAPABILITIES_LENGTH;
+            if !valid {
+                tracing::error!("Ignoring oversized capabilities of {}", client_id);
+            }
+            valid
+        });
+
+        // labels set by an admin take precedence over the reported ones
+        let labels = match self.store.upsert_client(&client_id, &name, &reported_labels, inventory.as_ref(), capabilities.as_ref()) {
+            Ok(labels) => labels,
+            Err(e) => {
+                tracing::error!("Unable to store client {}: {}", client_id, e);
+                reported_labels
+            }
+        };
+
+        // checking for another connection of the client and logging in is a single operation of the registry,
+        // so that two connections racing to log in can't both succeed
+        let replace = matches!(self.config.duplicate_client_policy, DuplicateClientPolicy::Replace);
+        let login = self.sessions.login_client(username, ClientSession {
+            id: client_id.clone(),
+            labels,
+            last_seen: now(),
+            latency_ms: None,
+            inventory,
+            capabilities,
+        }, replace);
+
+        match login {
+            Ok(Some(previous)) => {
+                tracing::info!("{} is already logged in through {}, replacing it with {}", client_id, previous, username);
+                self.close_connection(&previous);
+
+                // the old connection won't answer the jobs it was working on
+                self.handle_lost_jobs(&client_id).await;
+            }
+            Ok(None) => {}
+            Err(previous) => {
+                tracing::error!("{} is already logged in through {}, rejecting {}", client_id, previous, username);
+                return Err(RequestError::new(ErrorCode::DuplicateClient, "Client already connected"));
+            }
+        }
+
+        tracing::info!("{} logged in as client {}", username, client_id);
+        self.audit.record(AuditEvent::ClientLogin {
+            connection: username,
+            client_id: &client_id,
+        });
+        self.send_authenticated(username, &client_id).await;
+
+        self.send_clients_updates().await; // propagate the new list of logged in users to all the admins
+        self.dispatch_queued_jobs(&client_id).await;
+        Ok(())
+    }
+
+    async fn handle_run_request(&self, data: RunRequestBody, username: &str) -> Result<(), RequestError> {
+        let admin = self.require_admin(username, "run_request").await?;
+        let (accepted, dispatched) = self.create_jobs(&admin, data).await?;
+
+        // let the admin know which job IDs the results will be reported with
         self.send_messages(
-            &vec![username.clone()],
-            &serde_json::to_string(&logged_in_clients).unwrap(),
+            &[username.to_string()],
+            &Response::RunAccepted(accepted),
+        ).await;
+
+        for job in dispatched {
+            self.dispatch_job(job).await;
+        }
+        Ok(())
+    }
+
+    /// Run request issued through the HTTP API, the jobs are dispatched before answering
+    pub async fn api_run_request(&self, admin: &AdminSession, data: RunRequestBody) -> Result<RunAcceptedBody, RequestError> {
+        let (accepted, dispatched) = self.create_jobs(admin, data).await
+            .map_err(|e| self.audit_api_error(admin, "run_request", e))?;
+
+        for job in dispatched {
+            self.dispatch_job(job).await;
+        }
+
+        Ok(accepted)
+    }
+
+    /// Create the jobs of a run request, returning the acknowledgement for the admin
+    /// along with the jobs to dispatch right away
+    async fn create_jobs(&self, admin: &AdminSession, data: RunRequestBody) -> Result<(RunAcceptedBody, Vec<JobRecord>), RequestError> {
+        // the clients are about to be disconnected, the jobs would be lost
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(RequestError::new(ErrorCode::ShuttingDown, "The server is shutting down"));
+        }
+
+        let permissions = self.config.permissions(admin.role);
+        if !permissions.can_run(&data.module) {
+            return Err(RequestError::new(ErrorCode::PermissionDenied, format!("Role {} can't run {}", admin.role, data.module)));
+        }
+
+        let selector = match (data.target, data.selector) {
+            (Some(target), None) => Selector::Ids(vec![target]),
+            (None, Some(selector)) => selector,
+            _ => return Err(RequestError::new(ErrorCode::InvalidBody, "Run request needs either a target or a selector")),
+        };
+        let module = data.module;
+        let params = data.params;
+
+        // only jobs waiting for their target can expire, the longest expiries are shortened to the configured maximum
+        let expires_in = data.expires_in.unwrap_or(self.config.queue_expiry);
+        let expires_in_ms = expires_in
+            .checked_mul(1000)
+            .and_then(|expires_in_ms| i64::try_from(expires_in_ms).ok())
+            .ok_or_else(|| RequestError::new(ErrorCode::InvalidBody, format!("expires_in {} is out of range", expires_in)))?;
+        let mut queued_expires_at = now().saturating_add(expires_in_ms.min(self.config.max_queue_expiry as i64 * 1000));
+
+        tracing::info!("Selector: {:?}", selector);
+        tracing::info!("Module: {}", module);
+        tracing::info!("Params: {:?}", params);
+
+        let (targets, mut rejected) = match self.resolve_selector(&selector, data.queue_if_offline).await {
+            Ok(resolved) => resolved,
+            Err(e) => return Err(RequestError::new(ErrorCode::InvalidBody, format!("Invalid selector: {}", e))),
+        };
+
+        if targets.is_empty() && rejected.is_empty() {
+            return Err(RequestError::new(ErrorCode::NoTargets, "No client matches the selector"));
+        }
+
+        // clients check the signature, but catching mismatches here lets the admin know right away
+        let signed_command = match &data.signed {
+            Some(signed) => match signed.parse_command() {
+                Ok(command) if command.module != module || command.params != params => {
+                    return Err(RequestError::new(ErrorCode::InvalidBody, "Signed command doesn't match the request"));
+                }
+                Ok(command) if command.expires_at <= now() => {
+                    return Err(RequestError::new(ErrorCode::InvalidBody, "Signed command expired"));
+                }
+                Ok(command) => Some(command),
+                Err(e) => {
+                    return Err(RequestError::new(ErrorCode::InvalidBody, format!("Invalid signed command: {}", e)));
+                }
+            },
+            None => None,
+        };
+
+        let mut allowed_targets = Vec::new();
+        for (target, online) in targets {
+            let allowed = match self.client_labels(&target).await {
+                Some(labels) => permissions.can_target(&labels),
+                // an unknown client could come online with any labels
+                None => permissions.can_target_any(),
+            };
+
+            if !allowed {
+                tracing::info!("{} ({}) isn't allowed to target {}", admin.name, admin.role, target);
+                rejected.push(RejectedTarget {
+                    target,
+                    code: ErrorCode::PermissionDenied,
+                    reason: "Permission denied".to_string(),
+                });
+            } else if signed_command.as_ref().is_some_and(|command| !command.allows_target(&target)) {
+                rejected.push(RejectedTarget {
+                    target,
+                    code: ErrorCode::NotSigned,
+                    reason: "Not covered by the signature".to_string(),
+                });
+            } else if let Err(e) = self.check_capabilities(&target, &module, &params).await {
+                tracing::info!("{} can't run {}: {}", target, module, e);
+                rejected.push(RejectedTarget {
+                    target,
+                    code: e.code,
+                    reason: e.message,
+                });
+            } else {
+                allowed_targets.push((target, online));
+            }
+        }
+
+        // clients would refuse the command afterwards anyway
+        if let Some(command) = &signed_command {
+            queued_expires_at = queued_expires_at.min(command.expires_at);
+        }
+
+        let batch_id = nanoid!();
+        let mut jobs = BTreeMap::new();
+        let mut dispatched = Vec::new();
+
+        for (target, online) in allowed_targets {
+            let expires_at = if online { None } else { Some(queued_expires_at) };
+
+            let job = JobRecord {
+                job_id: nanoid!(),
+                batch_id: batch_id.clone(),
+                admin: admin.name.clone(),
+                target: target.clone(),
+                module: module.clone(),
+                params: params.clone(),
+                state: JobState::Queued,
+                created_at: now(),
+                expires_at,
+                dispatched_at: None,
+                started_at: None,
+                finished_at: None,
+                output: None,
+                signed: data.signed.clone(),
+            };
+
+            if let Err(e) = self.store.insert_job(&job) {
+                tracing::error!("Unable to store job: {}", e);
+                rejected.push(RejectedTarget {
+                    target,
+                    code: ErrorCode::InternalError,
+                    reason: "Unable to store job".to_string(),
+                });
+                continue;
+            }
+
+            tracing::info!("Job {} created by {} for {}", job.job_id, admin.name, target);
+
+            jobs.insert(job.job_id.clone(), JobSummary {
+                target,
+                state: if online { JobState::Dispatched } else { JobState::Queued },
+                expires_at,
+            });
+
+            if online {
+                dispatched.push(job);
+            } else {
+                tracing::info!("Job {} queued until {} comes online", job.job_id, job.target);
+            }
+        }
+
+        self.audit.record(AuditEvent::RunRequest {
+            admin: &admin.name,
+            batch_id: &batch_id,
+            module: &module,
+            params: &params,
+            jobs: jobs.iter().map(|(job_id, job)| (job_id.as_str(), job.target.as_str())).collect(),
+            rejected: &rejected,
+        });
+
+        Ok((RunAcceptedBody {
+            batch_id,
+            module,
+            jobs,
+            rejected,
+        }, dispatched))
+    }
+
+    /// Client IDs targeted by a selector along with whether they are online,
+    /// and the selected clients which can't be targeted
+    async fn resolve_selector(&self, selector: &Selector, queue_if_offline: bool) -> Result<(Vec<(String, bool)>, Vec<RejectedTarget>), String> {
+        let online_clients = self.client_infos();
+        let mut targets = Vec::new();
+        let mut rejected = Vec::new();
+
+        if let Selector::Ids(ids) = selector {
+            for id in ids {
+                let online = online_clients.iter().any(|client| &client.id == id);
+                if online || queue_if_offline {
+                    targets.push((id.clone(), online));
+                } else {
+                    tracing::error!("{} is not a client", id);
+                    rejected.push(RejectedTarget {
+                        target: id.clone(),
+                        code: ErrorCode::TargetOffline,
+                        reason: "Target is offline".to_string(),
+                    });
+                }
+            }
+            return Ok((targets, rejected));
+        }
+
+        let expression = match selector {
+            Selector::Labels(expression) => Some(LabelExpression::from_str(expression)?),
+            _ => None,
+        };
+        let selected = |client: &ClientInfo| match &expression {
+            Some(expression) => expression.matches(&client.labels),
+            None => true,
+        };
+
+        for client in online_clients.iter().filter(|client| selected(client)) {
+            targets.push((client.id.clone(), true));
+        }
+
+        // offline clients are only known from the database
+        if queue_if_offline {
+            let known_clients = self.store.list_clients().map_err(|e| e.to_string())?;
+            for client in known_clients.iter().filter(|client| selected(client)) {
+                if !online_clients.iter().any(|online| online.id == client.id) {
+                    targets.push((client.id.clone(), false));
+                }
+            }
+        }
+
+        Ok((targets, rejected))
+    }
+
+    /// Effective labels of a client, whether it is online or only known from the database
+    async fn client_labels(&self, client_id: &str) -> Option<Vec<String>> {
+        if let Some(labels) = self.sessions.client(client_id, |client| client.labels.clone()) {
+            return Some(labels);
+        }
+
+        match self.store.get_client(client_id) {
+            Ok(client) => client.map(|client| client.labels),
+            Err(e) => {
+                tracing::error!("Unable to fetch client {}: {}", client_id, e);
+                None
+            }
+        }
+    }
+
+    /// Check that a client advertised the module, and that the params match its schema
+    async fn check_capabilities(&self, client_id: &str, module: &str, params: &Value) -> Result<(), RequestError> {
+        let capabilities = match self.sessions.client(client_id, |client| client.capabilities.clone()) {
+            Some(capabilities) => capabilities,
+            None => match self.store.get_client(client_id) {
+                Ok(client) => client.and_then(|client| client.capabilities),
+                Err(e) => {
+                    tracing::error!("Unable to fetch client {}: {}", client_id, e);
+                    None
+                }
+            },
+        };
+
+        let schema = match capabilities {
+            Some(capabilities) => match capabilities.get(module) {
+                Some(schema) => schema.clone(),
+                None => return Err(RequestError::new(ErrorCode::UnsupportedModule, format!("Unsupported module {}", module))),
+            },
+            // clients which don't advertise their capabilities check the params themselves
+            None => return Ok(()),
+        };
+
+        schema.validate(params).map_err(|e| RequestError::new(ErrorCode::InvalidParams, format!("Invalid params: {}", e)))
+    }
+
+    async fn dispatch_job(&self, job: JobRecord) {
+        self.metrics.job_dispatched();
+        self.audit.record(AuditEvent::JobDispatched {
+            job_id: &job.job_id,
+            target: &job.target,
+        });
+
+        self.send_to_client(
+            &job.target,
+            &Response::Run(RunBody {
+                job_id: job.job_id.clone(),
+                module: job.module,
+                params: job.params,
+                signed: job.signed,
+            }),
         ).await;
+
+        if let Err(e) = self.store.mark_dispatched(&job.job_id) {
+            tracing::error!("Unable to update job {}: {}", job.job_id, e);
+        }
     }
 
-    async fn handle_auth_request(&self, data: Value, username: &String) {
-        let data = match serde_json::from_value::<AuthRequestBody>(data) {
-            Ok(auth_request_body) => auth_request_body,
-            Err(_) => {
-                tracing::info!("Invalid auth request");
+    /// Send the jobs queued while the client was offline
+    async fn dispatch_queued_jobs(&self, client_id: &str) {
+        let jobs = match self.store.list_queued(client_id) {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                tracing::error!("Unable to fetch queued jobs of {}: {}", client_id, e);
                 return;
             }
         };
 
-        match data.app_key.as_str() {
-            env!("CLIENT_KEY") => {
-                self.logged_in_clients.write().await.push(username.clone());
-                self.send_clients_updates().await; // propagate the new list of logged in users to all the admins
+        for job in jobs {
+            // the client may have come back with different capabilities than when the job was queued
+            if let Err(RequestError { message: reason, .. }) = self.check_capabilities(client_id, &job.module, &job.params).await {
+                tracing::info!("Queued job {} can't run on {}: {}", job.job_id, client_id, reason);
+                if let Err(e) = self.store.mark_finished(&job.job_id, JobState::Failed, &reason) {
+                    tracing::error!("Unable to update job {}: {}", job.job_id, e);
+                }
+                self.metrics.job_finished(JobState::Failed);
+                self.audit.record(AuditEvent::JobFinished {
+                    job_id: &job.job_id,
+                    state: JobState::Failed,
+                    output: Some(&reason),
+                });
+                self.send_job_update(&job.job_id, true).await;
+                continue;
             }
-            env!("ADMIN_KEY") => self.logged_in_admins.write().await.push(username.clone()),
-            _ => tracing::error!("Invalid app key"),
+
+            tracing::info!("Dispatching queued job {} to {}", job.job_id, client_id);
+            let job_id = job.job_id.clone();
+            self.dispatch_job(job).await;
+            self.send_job_update(&job_id, false).await;
         }
     }
 
-    async fn handle_run_request(&self, data: Value, username: &String) {
-        let data = match serde_json::from_value::<RunRequestBody>(data) {
-            Ok(run_request_body) => run_request_body,
-            Err(_) => {
-                tracing::error!("Invalid run request body");
+    /// Drop the queued jobs which reached their expiry, letting their admins know
+    pub async fn expire_queued_jobs(&self) {
+        let job_ids = match self.store.mark_expired() {
+            Ok(job_ids) => job_ids,
+            Err(e) => {
+                tracing::error!("Unable to expire queued jobs: {}", e);
                 return;
             }
         };
 
-        let target = data.target;
-        let module = data.module;
-        let params = data.params;
+        for job_id in job_ids {
+            tracing::info!("Job {} expired", job_id);
+            self.metrics.job_finished(JobState::Expired);
+            self.audit.record(AuditEvent::JobFinished {
+                job_id: &job_id,
+                state: JobState::Expired,
+                output: None,
+            });
+            self.send_job_update(&job_id, true).await;
+        }
+    }
 
-        tracing::info!("Target: {}", target);
-        tracing::info!("Module: {}", module);
-        tracing::info!("Params: {:?}", params);
+    /// Send the current state of a job to its admin and subscribers
+    async fn send_job_update(&self, job_id: &str, finished: bool) {
+        self.job_updates.notify_waiters();
+
+        let job = match self.store.get_job(job_id) {
+            Ok(Some(job)) => job,
+            _ => return,
+        };
+
+        let recipients = match finished {
+            true => self.job_recipients(&job).await,
+            false => {
+                let mut recipients = self.job_subscribers.read().await
+                    .get(job_id)
+                    .cloned()
+                    .unwrap_or_default();
+                recipients.extend(self.sessions.admin_usernames(Some(&job.admin)));
+                recipients.sort();
+                recipients.dedup();
+                recipients
+            }
+        };
+
+        self.send_messages(&recipients, &Response::Job(job)).await;
+    }
+
+    /// Fetch a job which is still waiting for a response from the client logged in through `username`
+    async fn get_in_flight_job(&self, job_id: &str, username: &str) -> Result<JobRecord, RequestError> {
+        let client_id = self.sessions.client_id(username)
+            .ok_or_else(|| RequestError::new(ErrorCode::Unauthorized, "Not logged in as a client"))?;
 
-        if !self.logged_in_clients.read().await.contains(&target) {
-            tracing::error!("{} is not a client", target);
-            self.send_messages(
-                &vec![username.clone()],
-                &BasicRequestResponse::new(
-                    "run".to_string(),
-                    serde_json::json!({
-                        "error": "Target isn't a client",
-                    }),
-                ).to_json_string(),
-            ).await;
-            return;
+        let job = self.fetch_job(job_id)?;
+
+        // only the targeted client is allowed to update a job
+        if job.target != client_id {
+            return Err(RequestError::new(ErrorCode::PermissionDenied, format!("Job {} doesn't target this client", job_id)));
+        }
+
+        if job.state.is_finished() {
+            return Err(RequestError::new(ErrorCode::InvalidBody, format!("Job {} is already {}", job_id, job.state)));
+        }
+
+        Ok(job)
+    }
+
+    async fn handle_run_started(&self, data: RunStartedBody, username: &str) -> Result<(), RequestError> {
+        self.get_in_flight_job(&data.job_id, username).await?;
+
+        if let Err(e) = self.store.mark_running(&data.job_id) {
+            tracing::error!("Unable to update job {}: {}", data.job_id, e);
+        }
+        Ok(())
+    }
+
+    async fn handle_run_response(&self, data: RunResponseBody, username: &str) -> Result<(), RequestError> {
+        let job = self.get_in_flight_job(&data.job_id, username).await?;
+
+        let state = if data.success { JobState::Succeeded } else { JobState::Failed };
+        if let Err(e) = self.store.mark_finished(&data.job_id, state, &data.output) {
+            tracing::error!("Unable to update job {}: {}", data.job_id, e);
         }
+        self.job_updates.notify_waiters();
 
+        tracing::info!("Job {} {} on {}", data.job_id, state, job.target);
+        self.metrics.job_finished(state);
+        if let Some(dispatched_at) = job.dispatched_at {
+            self.metrics.job_duration((now() - dispatched_at).max(0) as f64 / 1000.0);
+        }
+        self.audit.record(AuditEvent::JobFinished {
+            job_id: &data.job_id,
+            state,
+            output: Some(&data.output),
+        });
 
+        // route the response to the admin who issued the job and to its subscribers
         self.send_messages(
-            &vec![target],
-            &BasicRequestResponse::new(
-                "run".to_string(),
-                serde_json::json!({
-                    "module": module,
-                    "params": params,
-                }),
-            ).to_json_string(),
+            &self.job_recipients(&job).await,
+            &Response::RunResponse(data),
         ).await;
+        Ok(())
+    }
+
+    async fn handle_lost_jobs(&self, client_id: &str) {
+        let job_ids = match self.store.mark_target_lost(client_id) {
+            Ok(job_ids) => job_ids,
+            Err(e) => {
+                tracing::error!("Unable to update jobs of {}: {}", client_id, e);
+                return;
+            }
+        };
+
+        for job_id in job_ids {
+            tracing::info!("Job {} lost", job_id);
+            self.metrics.job_finished(JobState::Lost);
+            self.audit.record(AuditEvent::JobFinished {
+                job_id: &job_id,
+                state: JobState::Lost,
+                output: None,
+            });
+            self.send_job_update(&job_id, true).await;
+        }
+    }
+
+    /// Connections of the admin who issued a finished job and its subscribers, which are forgotten afterwards
+    async fn job_recipients(&self, job: &JobRecord) -> Vec<String> {
+        let mut recipients = self.job_subscribers.write().await
+            .remove(&job.job_id)
+            .unwrap_or_default();
+        recipients.extend(self.sessions.admin_usernames(Some(&job.admin)));
+        recipients.sort();
+        recipients.dedup();
+        recipients
     }
 
-    async fn handle_run_response(&self, data: Value) {
-        // propagate the response to all the admins
+    async fn handle_subscribe_job_request(&self, data: JobSubscriptionBody, username: &str) -> Result<(), RequestError> {
+        let admin = self.require_admin(username, "subscribe_job_request").await?;
+        let job = self.fetch_job(&data.job_id)?;
+
+        // nothing to wait for, send the result right away
+        if job.state.is_finished() {
+            self.send_messages(&[username.to_string()], &Response::Job(job)).await;
+            return Ok(());
+        }
+
+        // the connections of the issuing admin already receive the result
+        if job.admin != admin.name {
+            let mut job_subscribers = self.job_subscribers.write().await;
+            let subscribers = job_subscribers.entry(data.job_id.clone()).or_default();
+            if !subscribers.iter().any(|x| x == username) {
+                subscribers.push(username.to_string());
+            }
+        }
+
+        tracing::info!("{} subscribed to job {}", username, data.job_id);
+        Ok(())
+    }
+
+    async fn handle_list_jobs_request(&self, data: JobFilter, username: &str) -> Result<(), RequestError> {
+        self.require_admin(username, "list_jobs_request").await?;
+
+        let jobs = self.store.list_jobs(&data).map_err(|e| {
+            tracing::error!("Unable to list jobs: {}", e);
+            RequestError::new(ErrorCode::InternalError, "Unable to list jobs")
+        })?;
+
         self.send_messages(
-            &self.logged_in_admins.read().await.clone(),
-            &BasicRequestResponse::new(
-                "run_response".to_string(),
-                data,
-            ).to_json_string(),
+            &[username.to_string()],
+            &Response::JobList(JobListBody { jobs }),
         ).await;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    async fn handle_get_job_request(&self, data: GetJobRequestBody, username: &str) -> Result<(), RequestError> {
+        self.require_admin(username, "get_job_request").await?;
+
+        let job = self.fetch_job(&data.job_id)?;
+        self.send_messages(&[username.to_string()], &Response::Job(job)).await;
+        Ok(())
+    }
+
+    /// Job fetched through the HTTP API, waiting up to `wait` for it to finish
+    pub async fn api_get_job(&self, job_id: &str, wait: Duration) -> Result<JobRecord, RequestError> {
+        let deadline = tokio::time::Instant::now() + wait;
+
+        loop {
+            // registered before fetching the job so that an update in between isn't missed
+            let updated = self.job_updates.notified();
+            tokio::pin!(updated);
+            updated.as_mut().enable();
+
+            let job = self.fetch_job(job_id)?;
+            if job.state.is_finished() {
+                return Ok(job);
+            }
+
+            if tokio::time::timeout_at(deadline, updated).await.is_err() {
+                return self.fetch_job(job_id);
+            }
+        }
+    }
+
+    fn fetch_job(&self, job_id: &str) -> Result<JobRecord, RequestError> {
+        match self.store.get_job(job_id) {
+            Ok(Some(job)) => Ok(job),
+            Ok(None) => Err(RequestError::new(ErrorCode::NotFound, format!("Unknown job {}", job_id))),
+            Err(e) => {
+                tracing::error!("Unable to fetch job {}: {}", job_id, e);
+                Err(RequestError::new(ErrorCode::InternalError, "Unable to fetch job"))
+            }
+        }
+    }
+
+    async fn handle_set_labels_request(&self, data: SetLabelsRequestBody, username: &str) -> Result<(), RequestError> {
+        let admin = self.require_admin(username, "set_labels_request").await?;
+
+        if !admin.role.can_edit_labels() {
+            return Err(RequestError::new(ErrorCode::PermissionDenied, format!("Role {} can't edit labels", admin.role)));
+        }
+
+        if let Some(label) = data.labels.iter().flatten().find(|label| !is_valid_label(label)) {
+            return Err(RequestError::new(ErrorCode::InvalidBody, format!("Invalid label {:?}", label)));
+        }
+
+        let client = match self.store.set_client_labels(&data.client_id, data.labels.as_deref()) {
+            Ok(Some(client)) => client,
+            Ok(None) => return Err(RequestError::new(ErrorCode::NotFound, format!("Unknown client {}", data.client_id))),
+            Err(e) => {
+                tracing::error!("Unable to update labels of {}: {}", data.client_id, e);
+                return Err(RequestError::new(ErrorCode::InternalError, "Unable to update labels"));
+            }
+        };
+
+        tracing::info!("Labels of {} set to {:?} by {}", client.id, client.labels, admin.name);
+        self.audit.record(AuditEvent::LabelsSet {
+            admin: &admin.name,
+            client_id: &client.id,
+            labels: data.labels.as_deref(),
+        });
+
+        self.sessions.update_client(&client.id, |session| session.labels = client.labels);
+
+        self.send_clients_updates().await;
+        Ok(())
+    }
+
+    /// Session of the admin logged in through `username`, denying `action` to anyone else
+    async fn require_admin(&self, username: &str, action: &str) -> Result<AdminSession, RequestError> {
+        match self.sessions.admin(username) {
+            Some(session) => Ok(session),
+            None => {
+                let reason = "Not logged in as an admin";
+                self.record_permission_denied(username, None, action, reason);
+                Err(RequestError::new(ErrorCode::Unauthorized, reason))
+            }
+        }
+    }
+
+    /// Admin account of an HTTP API request, authenticated by its `Authorization: Bearer <key>` header
+    pub fn api_admin(&self, authorization: Option<&str>, address: Option<SocketAddr>) -> Result<AdminSession, RequestError> {
+        let address = address.map(|address| address.ip());
+        if address.is_some_and(|address| self.lockout.is_locked(address)) {
+            return Err(self.locked_out_error());
+        }
+
+        let admin = authorization
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .and_then(|key| self.config.find_admin(key.trim()));
+
+        match admin {
+            Some(admin) => {
+                if let Some(address) = address {
+                    self.lockout.record_success(address);
+                }
+                Ok(AdminSession {
+                    name: admin.name.clone(),
+                    role: admin.role,
+                })
+            }
+            None => {
+                tracing::error!("Invalid API token");
+                self.audit.record(AuditEvent::AuthFailed { connection: API_CONNECTION });
+                self.metrics.auth_failed();
+
+                if address.is_some_and(|address| self.lockout.record_failure(address)) {
+                    tracing::info!("{} locked out after {} failed authentications", API_CONNECTION, self.config.max_auth_failures);
+                    self.audit.record(AuditEvent::LockedOut {
+                        connection: API_CONNECTION,
+                        address,
+                    });
+                    return Err(self.locked_out_error());
+                }
+                Err(RequestError::new(ErrorCode::Unauthorized, "Missing or invalid admin token"))
+            }
+        }
+    }
+
+    /// Record the denials of HTTP API requests, as done for the ones sent through a connection
+    fn audit_api_error(&self, admin: &AdminSession, action: &str, error: RequestError) -> RequestError {
+        if error.code == ErrorCode::PermissionDenied {
+            self.record_permission_denied(API_CONNECTION, Some(&admin.name), action, &error.message);
+        }
+        error
+    }
+
+    fn record_permission_denied(&self, connection: &str, admin: Option<&str>, action: &str, reason: &str) {
+        tracing::info!("{} denied to {}: {}", action, connection, reason);
+        self.audit.record(AuditEvent::PermissionDenied {
+            connection,
+            admin,
+            action,
+            reason,
+        });
+    }
+
+    /// Let the sender of a request know why it failed, denials being audited as well
+    async fn send_request_error(&self, username: &str, action: Option<&str>, request_id: Option<String>, error: RequestError) {
+        if let (ErrorCode::PermissionDenied, Some(action)) = (error.code, action) {
+            let admin = self.sessions.admin(username).map(|session| session.name);
+            self.record_permission_denied(username, admin.as_deref(), action, &error.message);
+        }
+
+        let response = Response::Error(ErrorBody {
+            code: error.code,
+            message: error.message,
+            action: action.map(str::to_string),
+            request_id,
+        });
+        self.send_messages(&[username.to_string()], &response).await;
+    }
+}
+
+/// Parse a request, along with its `request_id` and `action` so that they can be sent back if it's invalid
+fn parse_request(text: &str) -> (Option<String>, Option<String>, Result<Request, RequestError>) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => return (None, None, Err(RequestError::new(ErrorCode::ParseError, format!("Invalid JSON: {}", e)))),
+    };
+
+    let request_id = match value.get("request_id") {
+        Some(Value::String(request_id)) => Some(request_id.clone()),
+        Some(Value::Number(request_id)) => Some(request_id.to_string()),
+        _ => None,
+    };
+
+    let action = match value.get("action").and_then(Value::as_str) {
+        Some(action) => action.to_string(),
+        None => return (request_id, None, Err(RequestError::new(ErrorCode::InvalidBody, "Missing action"))),
+    };
+
+    if RequestAction::from_str(&action).is_err() {
+        let error = RequestError::new(ErrorCode::UnknownAction, format!("Unknown action {}", action));
+        return (request_id, Some(action), Err(error));
+    }
+
+    let request = serde_json::from_value(value)
+        .map_err(|e| RequestError::new(ErrorCode::InvalidBody, format!("Invalid {}: {}", action, e)));
+    (request_id, Some(action), request)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::SlowConsumerPolicy;
+
+    /// Handler with its database and audit log in a directory removed once dropped,
+    /// `ck` being the client key and `ak` the admin key
+    fn handler(flags: &[&str]) -> (tempfile::TempDir, RequestsHandler) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut all_flags = vec!["--client-key", "ck", "--admin-key", "ak"];
+        all_flags.extend(flags);
+
+        let config = Arc::new(Config::from_flags(&all_flags).unwrap());
+        let store = Store::open(&dir.path().join("ws-server.db")).unwrap();
+        let audit = AuditLog::open(&dir.path().join("audit.log"), store.clone()).unwrap();
+
+        (dir, RequestsHandler::new(config, store, audit, Arc::default()))
+    }
+
+    async fn connect(handler: &RequestsHandler, username: &str, address: &str) -> Outbox {
+        let outbox = Outbox::new(64, SlowConsumerPolicy::DropOldest);
+        handler.handle_new_socket_connection(username, username, Some(address.parse().unwrap()), &outbox).await;
+        outbox
+    }
+
+    async fn send(handler: &RequestsHandler, username: &str, request: Value) {
+        handler.handle_request(Message::text(request.to_string()), username).await;
+    }
+
+    /// Responses queued for a connection so far, the other messages are skipped
+    async fn responses(outbox: &Outbox) -> Vec<Response> {
+        let mut responses = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(10), outbox.pop()).await {
+            if let Ok(text) = message.to_str() {
+                responses.push(serde_json::from_str(text).unwrap());
+            }
+        }
+        responses
+    }
+
+    fn error_codes(responses: &[Response]) -> Vec<ErrorCode> {
+        responses
+            .iter()
+            .filter_map(|response| match response {
+                Response::Error(error) => Some(error.code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn auth(app_key: &str) -> Value {
+        json!({"action": "auth_request", "data": {"app_key": app_key}})
+    }
+
+    #[tokio::test]
+    async fn permission_denied_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("ws-server.toml");
+        std::fs::write(&config_path, "[[admins]]\nname = \"val\"\nkey = \"vk\"\nrole = \"viewer\"\n").unwrap();
+        let (_dir, handler) = handler(&["--config", config_path.to_str().unwrap()]);
+
+        let viewer = connect(&handler, "viewer", "10.0.0.1").await;
+        send(&handler, "viewer", auth("vk")).await;
+        responses(&viewer).await;
+        send(&handler, "viewer", json!({
+            "action": "run_request",
+            "request_id": "r1",
+            "data": {"target": "somewhere", "module": "exec", "params": {}},
+        })).await;
+
+        let responses = responses(&viewer).await;
+        match responses.as_slice() {
+            [Response::Error(error)] => {
+                assert_eq!(error.code, ErrorCode::PermissionDenied);
+                assert_eq!(error.action.as_deref(), Some("run_request"));
+                assert_eq!(error.request_id.as_deref(), Some("r1"));
+            }
+            _ => panic!("unexpected responses {:?}", responses),
+        }
+    }
+
+    #[tokio::test]
+    async fn lockout_applies_across_reconnects() {
+        let (_dir, handler) = handler(&["--max-auth-failures", "2"]);
+
+        // each attempt on a new connection, as a brute-forcer would reconnect
+        let first = connect(&handler, "first", "10.0.0.1").await;
+        send(&handler, "first", auth("bad")).await;
+        assert_eq!(error_codes(&responses(&first).await), [ErrorCode::Unauthorized]);
+        handler.handle_disconnected_socket("first").await;
+
+        let second = connect(&handler, "second", "10.0.0.1").await;
+        send(&handler, "second", auth("bad")).await;
+        assert_eq!(error_codes(&responses(&second).await), [ErrorCode::LockedOut]);
+        handler.handle_disconnected_socket("second").await;
+
+        // even the right key is refused from the locked address
+        let third = connect(&handler, "third", "10.0.0.1").await;
+        send(&handler, "third", auth("ak")).await;
+        assert_eq!(error_codes(&responses(&third).await), [ErrorCode::LockedOut]);
+        assert!(!handler.is_authenticated("third"));
+
+        let other = connect(&handler, "other", "10.0.0.2").await;
+        send(&handler, "other", auth("ak")).await;
+        assert!(error_codes(&responses(&other).await).is_empty());
+        assert!(handler.is_authenticated("other"));
+    }
+
+    #[tokio::test]
+    async fn run_request_expiries_are_bounded() {
+        let (_dir, handler) = handler(&["--queue-expiry", "30", "--max-queue-expiry", "60"]);
+
+        let admin = connect(&handler, "admin", "10.0.0.1").await;
+        send(&handler, "admin", auth("ak")).await;
+        responses(&admin).await;
+
+        send(&handler, "admin", json!({
+            "action": "run_request",
+            "data": {"target": "offline", "module": "exec", "params": {}, "queue_if_offline": true, "expires_in": u64::MAX},
+        })).await;
+        assert_eq!(error_codes(&responses(&admin).await), [ErrorCode::InvalidBody]);
+
+        send(&handler, "admin", json!({
+            "action": "run_request",
+            "data": {"target": "offline", "module": "exec", "params": {}, "queue_if_offline": true, "expires_in": 3600},
+        })).await;
+        let responses = responses(&admin).await;
+        let job_id = match responses.as_slice() {
+            [Response::RunAccepted(accepted)] => accepted.jobs.keys().next().unwrap().clone(),
+            _ => panic!("unexpected responses {:?}", responses),
+        };
+        let job = handler.store.get_job(&job_id).unwrap().unwrap();
+        assert!(job.expires_at.unwrap() <= now() + 60 * 1000);
+    }
+
+    #[tokio::test]
+    async fn label_selector_targets_matching_clients() {
+        let (_dir, handler) = handler(&[]);
+
+        for (id, labels) in [("lab-1", json!(["lab"])), ("lab-laptop", json!(["lab", "laptop"])), ("office", json!(["office"]))] {
+            let client = connect(&handler, id, "10.0.0.1").await;
+            send(&handler, id, json!({"action": "auth_request", "data": {"app_key": "ck", "machine_id": id, "labels": labels}})).await;
+            responses(&client).await;
+        }
+
+        let admin = connect(&handler, "admin", "10.0.0.2").await;
+        send(&handler, "admin", auth("ak")).await;
+        responses(&admin).await;
+
+        send(&handler, "admin", json!({
+            "action": "run_request",
+            "data": {"selector": {"labels": "lab&!laptop"}, "module": "exec", "params": {}},
+        })).await;
+        let responses = responses(&admin).await;
+        match responses.as_slice() {
+            [Response::RunAccepted(accepted)] => {
+                let targets: Vec<&str> = accepted.jobs.values().map(|job| job.target.as_str()).collect();
+                assert_eq!(targets, ["lab-1"]);
+            }
+            _ => panic!("unexpected responses {:?}", responses),
+        }
+    }
+
+    fn client_auth(machine_id: &str) -> Value {
+        json!({"action": "auth_request", "data": {"app_key": "ck", "machine_id": machine_id}})
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_is_rejected() {
+        let (_dir, handler) = handler(&["--duplicate-client-policy", "reject"]);
+
+        let first = connect(&handler, "first", "10.0.0.1").await;
+        send(&handler, "first", client_auth("machine")).await;
+        assert!(error_codes(&responses(&first).await).is_empty());
+
+        let second = connect(&handler, "second", "10.0.0.1").await;
+        send(&handler, "second", client_auth("machine")).await;
+        assert_eq!(error_codes(&responses(&second).await), [ErrorCode::DuplicateClient]);
+        assert!(!handler.is_authenticated("second"));
+        assert_eq!(handler.sessions.client_username("machine").as_deref(), Some("first"));
+    }
+
+    #[tokio::test]
+    async fn duplicate_client_replaces_the_previous_connection() {
+        let (_dir, handler) = handler(&[]);
+
+        let first = connect(&handler, "first", "10.0.0.1").await;
+        send(&handler, "first", client_auth("machine")).await;
+        responses(&first).await;
+
+        let second = connect(&handler, "second", "10.0.0.1").await;
+        send(&handler, "second", client_auth("machine")).await;
+        assert!(error_codes(&responses(&second).await).is_empty());
+        assert_eq!(handler.sessions.client_username("machine").as_deref(), Some("second"));
+
+        // the previous connection is closed
+        let mut closed = false;
+        while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(10), first.pop()).await {
+            closed |= message.is_close();
+        }
+        assert!(closed);
+    }
+
+    #[tokio::test]
+    async fn run_request_without_targets() {
+        let (_dir, handler) = handler(&[]);
+
+        let admin = connect(&handler, "admin", "10.0.0.1").await;
+        send(&handler, "admin", auth("ak")).await;
+        responses(&admin).await;
+
+        for selector in [json!({"ids": []}), json!({"labels": "nowhere"}), json!("all")] {
+            send(&handler, "admin", json!({
+                "action": "run_request",
+                "data": {"selector": selector, "module": "exec", "params": {}, "queue_if_offline": true},
+            })).await;
+            assert_eq!(error_codes(&responses(&admin).await), [ErrorCode::NoTargets], "selector {}", selector);
+        }
+
+        // an offline target is a different failure
+        send(&handler, "admin", json!({
+            "action": "run_request",
+            "data": {"target": "offline", "module": "exec", "params": {}},
+        })).await;
+        let responses = responses(&admin).await;
+        match responses.as_slice() {
+            [Response::RunAccepted(accepted)] => assert_eq!(accepted.rejected[0].code, ErrorCode::TargetOffline),
+            _ => panic!("unexpected responses {:?}", responses),
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use nack_protocol::{Capabilities, ClientInfo, ClientInventory};
+
+use crate::outbox::Outbox;
+use crate::permissions::Role;
+
+/// Number of independently locked parts of the registry, connections hashed to different shards don't contend
+const SHARDS: usize = 64;
+
+/// Open connection, keyed by its username in the registry
+pub struct Session {
+    /// Name the connection was opened with, only used for display
+    pub name: String,
+    /// Peer address, unknown behind the TLS listener
+    pub address: Option<IpAddr>,
+    pub outbox: Outbox,
+    pub state: SessionState,
+    /// Bad keys sent through this connection, only counted when the peer address is unknown
+    pub auth_failures: u32,
+}
+
+/// Authentication state of a connection, which can only authenticate once
+pub enum SessionState {
+    /// Waiting for an auth request, the connection is closed if it doesn't come in time
+    Connected,
+    Authenticated(Principal),
+    /// Logged in as a client which then logged in again through a newer connection, waiting to be closed
+    Replaced,
+}
+
+pub enum Principal {
+    Client(Box<ClientSession>),
+    Admin(AdminSession),
+}
+
+/// Logged in client, also indexed by its machine ID
+#[derive(Clone)]
+pub struct ClientSession {
+    pub id: String,
+    pub labels: Vec<String>,
+    pub last_seen: i64,
+    pub latency_ms: Option<u64>,
+    pub inventory: Option<ClientInventory>,
+    pub capabilities: Option<Capabilities>,
+}
+
+/// Logged in admin
+#[derive(Clone)]
+pub struct AdminSession {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Sessions of every open connection along with indexes of the authenticated ones,
+/// locks are never held across an await point nor while sending
+pub struct SessionRegistry {
+    sessions: Sharded<Session>,
+    /// Username of the connection of each logged in client, keyed by machine ID
+    clients: Sharded<String>,
+    /// Usernames of the connections logged in as an admin
+    admins: RwLock<HashSet<String>>,
+}
+
+/// Map split into shards locked on their own, keyed by strings
+struct Sharded<V> {
+    shards: Box<[RwLock<HashMap<String, V>>]>,
+    hasher: RandomState,
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Client(client) => write!(f, "client {}", client.id),
+            Principal::Admin(admin) => write!(f, "admin {}", admin.name),
+        }
+    }
+}
+
+impl Session {
+    pub fn new(name: &str, address: Option<IpAddr>, outbox: Outbox) -> Session {
+        Session {
+            name: name.to_string(),
+            address,
+            outbox,
+            state: SessionState::Connected,
+            auth_failures: 0,
+        }
+    }
+
+    pub fn client(&self) -> Option<&ClientSession> {
+        match &self.state {
+            SessionState::Authenticated(Principal::Client(client)) => Some(client.as_ref()),
+            _ => None,
+        }
+    }
+
+    pub fn admin(&self) -> Option<&AdminSession> {
+        match &self.state {
+            SessionState::Authenticated(Principal::Admin(admin)) => Some(admin),
+            _ => None,
+        }
+    }
+}
+
+impl ClientSession {
+    /// Client as listed to the admins, `name` being the one of its connection
+    pub fn info(&self, name: &str) -> ClientInfo {
+        ClientInfo {
+            id: self.id.clone(),
+            name: name.to_string(),
+            labels: self.labels.clone(),
+            last_seen: Some(self.last_seen),
+            latency_ms: self.latency_ms,
+            inventory: self.inventory.clone(),
+            capabilities: self.capabilities.clone(),
+        }
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> SessionRegistry {
+        SessionRegistry {
+            sessions: Sharded::new(),
+            clients: Sharded::new(),
+            admins: RwLock::default(),
+        }
+    }
+
+    pub fn insert(&self, username: &str, session: Session) {
+        self.sessions.write(username).insert(username.to_string(), session);
+    }
+
+    /// Forget a connection, returning the ID of the client it was logged in as unless it was replaced
+    pub fn remove(&self, username: &str) -> Option<String> {
+        let session = self.sessions.write(username).remove(username)?;
+
+        match session.state {
+            SessionState::Authenticated(Principal::Client(client)) => {
+                // a newer connection of the client may have taken over the index already
+                let mut clients = self.clients.write(&client.id);
+                if clients.get(&client.id).is_some_and(|current| current == username) {
+                    clients.remove(&client.id);
+                    return Some(client.id);
+                }
+                None
+            }
+            SessionState::Authenticated(Principal::Admin(_)) => {
+                self.admins.write().unwrap().remove(username);
+                None
+            }
+            SessionState::Connected | SessionState::Replaced => None,
+        }
+    }
+
+    pub fn get<R>(&self, username: &str, f: impl FnOnce(&Session) -> R) -> Option<R> {
+        self.sessions.read(username).get(username).map(f)
+    }
+
+    pub fn update<R>(&self, username: &str, f: impl FnOnce(&mut Session) -> R) -> Option<R> {
+        self.sessions.write(username).get_mut(username).map(f)
+    }
+
+    pub fn outbox(&self, username: &str) -> Option<Outbox> {
+        self.get(username, |session| session.outbox.clone())
+    }
+
+    /// Outboxes of the connections which are still open, each shard being locked once
+    pub fn outboxes<'a>(&self, usernames: &'a [String]) -> Vec<(&'a str, Outbox)> {
+        let mut keyed: Vec<(usize, &str)> = usernames
+            .iter()
+            .map(|username| (self.sessions.index(username), username.as_str()))
+            .collect();
+        keyed.sort_unstable();
+
+        let mut outboxes = Vec::with_capacity(keyed.len());
+        for chunk in keyed.chunk_by(|a, b| a.0 == b.0) {
+            let shard = self.sessions.shards[chunk[0].0].read().unwrap();
+            for (_, username) in chunk {
+                if let Some(session) = shard.get(*username) {
+                    outboxes.push((*username, session.outbox.clone()));
+                }
+            }
+        }
+        outboxes
+    }
+
+    /// Authenticate a connection as a client, returning the connection it was logged in through if any.
+    /// That connection is replaced when `replace` is set, otherwise the login is refused with its username.
+    /// The index of the client stays locked throughout, so that two connections of a client can't both log in
+    pub fn login_client(&self, username: &str, client: ClientSession, replace: bool) -> Result<Option<String>, String> {
+        let client_id = client.id.clone();
+        let mut clients = self.clients.write(&client_id);
+
+        let previous = clients.get(&client_id).filter(|previous| *previous != username).cloned();
+        if let Some(previous) = &previous {
+            if !replace {
+                return Err(previous.clone());
+            }
+        }
+
+        if self.update(username, |session| session.state = SessionState::Authenticated(Principal::Client(Box::new(client)))).is_none() {
+            return Ok(None);
+        }
+        clients.insert(client_id, username.to_string());
+
+        if let Some(previous) = &previous {
+            self.update(previous, |session| session.state = SessionState::Replaced);
+        }
+        Ok(previous)
+    }
+
+    pub fn login_admin(&self, username: &str, admin: AdminSession) {
+        if self.update(username, |session| session.state = SessionState::Authenticated(Principal::Admin(admin))).is_some() {
+            self.admins.write().unwrap().insert(username.to_string());
+        }
+    }
+
+    /// Username of the connection a client is logged in through
+    pub fn client_username(&self, client_id: &str) -> Option<String> {
+        self.clients.read(client_id).get(client_id).cloned()
+    }
+
+    /// Machine ID of the client logged in through the `username` connection
+    pub fn client_id(&self, username: &str) -> Option<String> {
+        self.get(username, |session| session.client().map(|client| client.id.clone())).flatten()
+    }
+
+    pub fn client<R>(&self, client_id: &str, f: impl FnOnce(&ClientSession) -> R) -> Option<R> {
+        let username = self.client_username(client_id)?;
+        self.get(&username, |session| session.client().map(f)).flatten()
+    }
+
+    pub fn update_client<R>(&self, client_id: &str, f: impl FnOnce(&mut ClientSession) -> R) -> Option<R> {
+        let username = self.client_username(client_id)?;
+        self.update(&username, |session| match &mut session.state {
+            SessionState::Authenticated(Principal::Client(client)) => Some(f(client)),
+            _ => None,
+        }).flatten()
+    }
+
+    /// Map every logged in client along with the name of its connection
+    pub fn map_clients<R>(&self, mut f: impl FnMut(&ClientSession, &str) -> R) -> Vec<R> {
+        let mut results = Vec::new();
+        for shard in self.sessions.shards.iter() {
+            for session in shard.read().unwrap().values() {
+                if let Some(client) = session.client() {
+                    results.push(f(client, &session.name));
+                }
+            }
+        }
+        results
+    }
+
+    pub fn admin(&self, username: &str) -> Option<AdminSession> {
+        self.get(username, |session| session.admin().cloned()).flatten()
+    }
+
+    /// Usernames of the connections logged in as an admin, only the ones of the account `name` when given
+    pub fn admin_usernames(&self, name: Option<&str>) -> Vec<String> {
+        let usernames: Vec<String> = self.admins.read().unwrap().iter().cloned().collect();
+        match name {
+            None => usernames,
+            Some(name) => usernames
+                .into_iter()
+                .filter(|username| self.admin(username).is_some_and(|admin| admin.name == name))
+                .collect(),
+        }
+    }
+
+    /// Usernames of every open connection, authenticated or not
+    pub fn usernames(&self) -> Vec<String> {
+        let mut usernames = Vec::new();
+        for shard in self.sessions.shards.iter() {
+            usernames.extend(shard.read().unwrap().keys().cloned());
+        }
+        usernames
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn admin_count(&self) -> usize {
+        self.admins.read().unwrap().len()
+    }
+}
+
+impl<V> Sharded<V> {
+    fn new() -> Sharded<V> {
+        Sharded {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % SHARDS as u64) as usize
+    }
+
+    fn read(&self, key: &str) -> std::sync::RwLockReadGuard<'_, HashMap<String, V>> {
+        self.shards[self.index(key)].read().unwrap()
+    }
+
+    fn write(&self, key: &str) -> std::sync::RwLockWriteGuard<'_, HashMap<String, V>> {
+        self.shards[self.index(key)].write().unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SlowConsumerPolicy;
+
+    fn connect(registry: &SessionRegistry, username: &str) {
+        registry.insert(username, Session::new(username, None, Outbox::new(1, SlowConsumerPolicy::DropOldest)));
+    }
+
+    fn client(id: &str) -> ClientSession {
+        ClientSession {
+            id: id.to_string(),
+            labels: Vec::new(),
+            last_seen: 0,
+            latency_ms: None,
+            inventory: None,
+            capabilities: None,
+        }
+    }
+
+    fn is_replaced(registry: &SessionRegistry, username: &str) -> bool {
+        registry.get(username, |session| matches!(session.state, SessionState::Replaced)).unwrap()
+    }
+
+    #[test]
+    fn replaced_connection_keeps_the_index() {
+        let registry = SessionRegistry::new();
+        connect(&registry, "old");
+        connect(&registry, "new");
+
+        assert_eq!(registry.login_client("old", client("machine"), true), Ok(None));
+        assert_eq!(registry.login_client("new", client("machine"), true), Ok(Some("old".to_string())));
+        assert!(is_replaced(&registry, "old"));
+        assert_eq!(registry.client_username("machine").as_deref(), Some("new"));
+
+        assert_eq!(registry.remove("old"), None);
+        assert_eq!(registry.client_id("new").as_deref(), Some("machine"));
+        assert_eq!(registry.remove("new").as_deref(), Some("machine"));
+        assert_eq!(registry.client_count(), 0);
+    }
+
+    #[test]
+    fn duplicate_login_is_refused() {
+        let registry = SessionRegistry::new();
+        connect(&registry, "first");
+        connect(&registry, "second");
+
+        assert_eq!(registry.login_client("first", client("machine"), false), Ok(None));
+        assert_eq!(registry.login_client("second", client("machine"), false), Err("first".to_string()));
+        assert_eq!(registry.client_username("machine").as_deref(), Some("first"));
+        assert!(registry.get("second", |session| matches!(session.state, SessionState::Connected)).unwrap());
+    }
+
+    #[test]
+    fn concurrent_duplicate_logins_let_one_in() {
+        const CONNECTIONS: usize = 16;
+        let registry = SessionRegistry::new();
+        for i in 0..CONNECTIONS {
+            connect(&registry, &format!("connection-{}", i));
+        }
+
+        let logged_in: Vec<String> = std::thread::scope(|scope| {
+            let logins: Vec<_> = (0..CONNECTIONS)
+                .map(|i| {
+                    let registry = &registry;
+                    scope.spawn(move || {
+                        let username = format!("connection-{}", i);
+                        registry.login_client(&username, client("machine"), false).ok().map(|_| username)
+                    })
+                })
+                .collect();
+            logins.into_iter().filter_map(|login| login.join().unwrap()).collect()
+        });
+
+        assert_eq!(logged_in.len(), 1);
+        assert_eq!(registry.client_username("machine"), logged_in.first().cloned());
+        assert_eq!(registry.map_clients(|client, _| client.id.clone()), ["machine"]);
+    }
+
+    #[test]
+    fn concurrent_logins_leave_one_connection_per_client() {
+        const THREADS: usize = 8;
+        const CLIENTS: usize = 100;
+        let registry = SessionRegistry::new();
+
+        // every thread logs in every client, replacing the connections of the other threads
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let registry = &registry;
+                scope.spawn(move || {
+                    for i in 0..CLIENTS {
+                        let username = format!("{}-{}", thread, i);
+                        connect(registry, &username);
+                        registry.login_client(&username, client(&format!("machine-{}", i)), true).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(registry.len(), THREADS * CLIENTS);
+        assert_eq!(registry.client_count(), CLIENTS);
+        assert_eq!(registry.map_clients(|client, _| client.id.clone()).len(), CLIENTS);
+        for i in 0..CLIENTS {
+            let current = registry.client_username(&format!("machine-{}", i)).unwrap();
+            for thread in 0..THREADS {
+                let username = format!("{}-{}", thread, i);
+                assert_eq!(is_replaced(&registry, &username), username != current);
+            }
+        }
+
+        // removing the replaced connections and the current ones at the same time
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let registry = &registry;
+                scope.spawn(move || {
+                    for i in 0..CLIENTS {
+                        registry.remove(&format!("{}-{}", thread, i));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(registry.len(), 0);
+        assert_eq!(registry.client_count(), 0);
+    }
+
+    #[test]
+    fn concurrent_replace_and_remove() {
+        let registry = SessionRegistry::new();
+        connect(&registry, "first");
+        registry.login_client("first", client("machine"), true).unwrap();
+
+        // a reconnection racing the removal of the connection it replaces
+        for round in 0..100 {
+            let previous = registry.client_username("machine").unwrap();
+            let username = format!("round-{}", round);
+            connect(&registry, &username);
+
+            std::thread::scope(|scope| {
+                scope.spawn(|| registry.login_client(&username, client("machine"), true).unwrap());
+                scope.spawn(|| registry.remove(&previous));
+            });
+
+            assert_eq!(registry.client_username("machine").as_deref(), Some(username.as_str()));
+            assert_eq!(registry.len(), 1);
+            assert_eq!(registry.client_count(), 1);
+        }
+    }
+}
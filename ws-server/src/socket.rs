@@ -1,71 +1,162 @@
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
 use nanoid::nanoid;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{Message, WebSocket};
 
 use requests_handler::RequestsHandler;
 
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::outbox::Outbox;
+use crate::store::{now, Store};
 use crate::requests_handler;
 
+const QUEUE_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time left to the writer of a closed connection to flush its queue before being aborted
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct SocketHandler {
     requests_handler: RequestsHandler,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
 }
 
 impl SocketHandler {
-    pub fn new() -> SocketHandler {
+    pub fn new(config: Arc<Config>, store: Store, audit: AuditLog) -> SocketHandler {
+        let metrics = Arc::new(Metrics::default());
+
         SocketHandler {
-            requests_handler: RequestsHandler::new(),
+            requests_handler: RequestsHandler::new(config.clone(), store, audit, metrics.clone()),
+            config,
+            metrics,
         }
     }
 
-    pub async fn handle_connection(self, ws: WebSocket, username: String) {
-        // Adding a random string to the username to make it unique
-        let username = format!("{}-{}", username, nanoid!(5));
+    pub fn requests_handler(&self) -> RequestsHandler {
+        self.requests_handler.clone()
+    }
+
+    /// Periodically drop the queued jobs which reached their expiry
+    pub fn spawn_queue_expiry(&self) {
+        let requests_handler = self.requests_handler.clone();
+
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(QUEUE_EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                requests_handler.expire_queued_jobs().await;
+            }
+        });
+    }
+
+    pub async fn handle_connection(self, ws: WebSocket, name: String, address: Option<SocketAddr>) {
+        // Adding a random string to the name to make the connection unique,
+        // the name itself is only used for display
+        let username = format!("{}-{}", name, nanoid!(5));
 
         // Split the socket into a sender and receive of messages.
         let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
 
-        // Use an unbounded channel to handle buffering and flushing of messages
-        // to the websocket...
-        let (tx, rx) = mpsc::unbounded_channel::<Message>();
-        let mut rx = UnboundedReceiverStream::new(rx);
+        // Use a bounded queue to handle buffering and flushing of messages
+        // to the websocket, a connection which doesn't keep up is handled by the slow consumer policy
+        let outbox = Outbox::new(self.config.send_queue_size, self.config.slow_consumer_policy);
 
-        tokio::task::spawn(async move {
-            while let Some(message) = rx.next().await {
-                user_ws_tx
-                    .send(message)
-                    .unwrap_or_else(|e| {
-                        tracing::error!("websocket send error: {}", e);
-                    })
-                    .await;
+        let metrics = self.metrics.clone();
+        let writer_outbox = outbox.clone();
+        let writer_username = username.clone();
+        let mut writer = tokio::task::spawn(async move {
+            while let Some(message) = writer_outbox.pop().await {
+                if let Err(e) = user_ws_tx.send(message).await {
+                    tracing::error!("websocket send error(uid={}): {}", writer_username, e);
+                    metrics.send_failed();
+                    break;
+                }
             }
         });
 
         // Save the sender in our list of connected users.
-        self.requests_handler.handle_new_socket_connection(&username, &tx).await;
+        self.requests_handler.handle_new_socket_connection(&username, &name, address.map(|address| address.ip()), &outbox).await;
 
         // Return a `Future` that is basically a state machine managing
         // this specific user's connection.
 
-        // Every time the user sends a message, broadcast it to
-        // all other users...
-        while let Some(result) = user_ws_rx.next().await {
-            let msg = match result {
-                Ok(msg) => msg,
-                Err(e) => {
-                    tracing::error!("websocket error(uid={}): {}", username, e);
-                    break;
+        // Ping the user periodically, silent connections are most likely half-open
+        // and would otherwise never be noticed
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(self.config.ping_interval));
+        let mut last_seen = Instant::now();
+
+        // connections which never authenticate would otherwise stay open forever, answering pings
+        let auth_deadline = tokio::time::sleep(Duration::from_secs(self.config.auth_timeout));
+        tokio::pin!(auth_deadline);
+        let mut authenticated = false;
+        let mut writer_done = false;
+
+        loop {
+            tokio::select! {
+                result = user_ws_rx.next() => {
+                    let msg = match result {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => {
+                            tracing::error!("websocket error(uid={}): {}", username, e);
+                            break;
+                        }
+                        None => break,
+                    };
+
+                    last_seen = Instant::now();
+
+                    if msg.is_text() {
+                        self.requests_handler.handle_request(msg, &username).await;
+                    } else if msg.is_pong() {
+                        // pings carry the time they were sent at
+                        if let Ok(sent_at) = <[u8; 8]>::try_from(msg.as_bytes()) {
+                            let latency_ms = (now() - i64::from_be_bytes(sent_at)).max(0) as u64;
+                            self.requests_handler.handle_pong(&username, latency_ms).await;
+                        }
+                    }
                 }
-            };
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > idle_timeout {
+                        tracing::info!("{} silent for {} seconds, closing the connection", username, idle_timeout.as_secs());
+                        self.metrics.idle_disconnected();
+                        let _ = outbox.push(Message::close());
+                        break;
+                    }
 
-            if msg.is_text() {
-                self.requests_handler.handle_request(msg, &username).await;
+                    let _ = outbox.push(Message::ping(now().to_be_bytes().to_vec()));
+                }
+                _ = &mut auth_deadline, if !authenticated => {
+                    if !self.requests_handler.is_authenticated(&username) {
+                        tracing::info!("{} didn't authenticate within {} seconds, closing the connection", username, self.config.auth_timeout);
+                        self.metrics.auth_timed_out();
+                        let _ = outbox.push(Message::close());
+                        break;
+                    }
+                    authenticated = true;
+                }
+                _ = outbox.overflowed() => break,
+                // the socket can't be written to anymore
+                _ = &mut writer => {
+                    writer_done = true;
+                    break;
+                }
             }
         }
 
+        // let the writer flush the queue, unless the peer stopped reading altogether
+        outbox.close();
+        if !writer_done && tokio::time::timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+
         // user_ws_rx stream will keep processing as long as the user stays
         // connected. Once they disconnect, then...
         self.requests_handler.handle_disconnected_socket(&username).await;
@@ -0,0 +1,407 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nack_protocol::{Capabilities, ClientInfo, ClientInventory, JobFilter, JobRecord, JobState};
+use rusqlite::types::ToSql;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use serde_json::Value;
+
+const DEFAULT_LIST_LIMIT: u32 = 100;
+
+/// Schema changes, applied in order and tracked through the `user_version` pragma
+const MIGRATIONS: &[&str] = &["
+CREATE TABLE IF NOT EXISTS jobs (
+    job_id        TEXT PRIMARY KEY,
+    admin         TEXT NOT NULL,
+    target        TEXT NOT NULL,
+    module        TEXT NOT NULL,
+    params        TEXT NOT NULL,
+    state         TEXT NOT NULL,
+    created_at    INTEGER NOT NULL,
+    dispatched_at INTEGER,
+    started_at    INTEGER,
+    finished_at   INTEGER,
+    output        TEXT
+);
+CREATE INDEX IF NOT EXISTS jobs_target ON jobs (target, state);
+CREATE INDEX IF NOT EXISTS jobs_created_at ON jobs (created_at);
+", "
+ALTER TABLE jobs ADD COLUMN expires_at INTEGER;
+", "
+ALTER TABLE jobs ADD COLUMN batch_id TEXT NOT NULL DEFAULT '';
+UPDATE jobs SET batch_id = job_id;
+CREATE INDEX IF NOT EXISTS jobs_batch_id ON jobs (batch_id);
+CREATE TABLE IF NOT EXISTS clients (
+    client_id       TEXT PRIMARY KEY,
+    name            TEXT NOT NULL,
+    reported_labels TEXT NOT NULL,
+    labels          TEXT,
+    last_seen       INTEGER NOT NULL
+);
+", "
+CREATE TABLE IF NOT EXISTS audit_head (
+    id   INTEGER PRIMARY KEY CHECK (id = 1),
+    seq  INTEGER NOT NULL,
+    hash TEXT NOT NULL
+);
+", "
+ALTER TABLE jobs ADD COLUMN signed TEXT;
+", "
+ALTER TABLE clients ADD COLUMN inventory TEXT;
+", "
+ALTER TABLE clients ADD COLUMN capabilities TEXT;
+"];
+
+/// Milliseconds since the unix epoch
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+/// Persistent job and client tables backed by an embedded SQLite database,
+/// along with the head of the audit log chain
+#[derive(Clone)]
+pub struct Store {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> rusqlite::Result<Store> {
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+
+        let store = Store {
+            connection: Arc::new(Mutex::new(connection)),
+        };
+
+        // jobs left in flight by a previous run will never get their response
+        let lost = store.mark_all_in_flight_lost()?;
+        if !lost.is_empty() {
+            tracing::info!("{} in flight jobs from a previous run marked as lost", lost.len());
+        }
+
+        Ok(store)
+    }
+
+    /// Open the database without migrating nor touching the jobs, for maintenance commands
+    pub fn open_read_only(path: &Path) -> rusqlite::Result<Store> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        Ok(Store {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn insert_job(&self, job: &JobRecord) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO jobs (job_id, batch_id, admin, target, module, params, state, created_at, expires_at, dispatched_at, started_at, finished_at, output, signed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            params![
+                job.job_id,
+                job.batch_id,
+                job.admin,
+                job.target,
+                job.module,
+                job.params.to_string(),
+                job.state.to_string(),
+                job.created_at,
+                job.expires_at,
+                job.dispatched_at,
+                job.started_at,
+                job.finished_at,
+                job.output,
+                job.signed.as_ref().map(|signed| serde_json::to_string(signed).unwrap()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_job(&self, job_id: &str) -> rusqlite::Result<Option<JobRecord>> {
+        self.connection.lock().unwrap()
+            .query_row("SELECT * FROM jobs WHERE job_id = ?1", [job_id], row_to_job)
+            .optional()
+    }
+
+    pub fn list_jobs(&self, filter: &JobFilter) -> rusqlite::Result<Vec<JobRecord>> {
+        let mut query = "SELECT * FROM jobs WHERE 1 = 1".to_string();
+        let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+        if let Some(batch_id) = &filter.batch_id {
+            values.push(Box::new(batch_id.clone()));
+            query.push_str(&format!(" AND batch_id = ?{}", values.len()));
+        }
+        if let Some(admin) = &filter.admin {
+            values.push(Box::new(admin.clone()));
+            query.push_str(&format!(" AND admin = ?{}", values.len()));
+        }
+        if let Some(target) = &filter.target {
+            values.push(Box::new(target.clone()));
+            query.push_str(&format!(" AND target = ?{}", values.len()));
+        }
+        if let Some(module) = &filter.module {
+            values.push(Box::new(module.clone()));
+            query.push_str(&format!(" AND module = ?{}", values.len()));
+        }
+        if let Some(state) = &filter.state {
+            values.push(Box::new(state.to_string()));
+            query.push_str(&format!(" AND state = ?{}", values.len()));
+        }
+
+        values.push(Box::new(filter.limit.unwrap_or(DEFAULT_LIST_LIMIT)));
+        query.push_str(&format!(" ORDER BY created_at DESC LIMIT ?{}", values.len()));
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&query)?;
+        let jobs = statement
+            .query_map(rusqlite::params_from_iter(values.iter()), row_to_job)?
+            .collect();
+        jobs
+    }
+
+    pub fn mark_dispatched(&self, job_id: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE jobs SET state = ?2, dispatched_at = ?3 WHERE job_id = ?1",
+            params![job_id, JobState::Dispatched.to_string(), now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn mark_running(&self, job_id: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE jobs SET state = ?2, started_at = ?3 WHERE job_id = ?1",
+            params![job_id, JobState::Running.to_string(), now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn mark_finished(&self, job_id: &str, state: JobState, output: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE jobs SET state = ?2, finished_at = ?3, output = ?4 WHERE job_id = ?1",
+            params![job_id, state.to_string(), now(), output],
+        )?;
+        Ok(())
+    }
+
+    /// Queued jobs waiting for `target` to come online, oldest first
+    pub fn list_queued(&self, target: &str) -> rusqlite::Result<Vec<JobRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT * FROM jobs
+             WHERE target = ?1 AND state = ?2 AND (expires_at IS NULL OR expires_at > ?3)
+             ORDER BY created_at",
+        )?;
+        let jobs = statement
+            .query_map(params![target, JobState::Queued.to_string(), now()], row_to_job)?
+            .collect();
+        jobs
+    }
+
+    /// Mark the queued jobs which reached their expiry, returning their IDs
+    pub fn mark_expired(&self) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "UPDATE jobs SET state = ?1, finished_at = ?2
+             WHERE state = ?3 AND expires_at <= ?2
+             RETURNING job_id",
+        )?;
+        let job_ids = statement
+            .query_map(
+                params![JobState::Expired.to_string(), now(), JobState::Queued.to_string()],
+                |row| row.get(0),
+            )?
+            .collect();
+        job_ids
+    }
+
+    /// Mark the jobs a disconnected client was working on as lost, returning their IDs
+    pub fn mark_target_lost(&self, target: &str) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "UPDATE jobs SET state = ?2, finished_at = ?3
+             WHERE target = ?1 AND state IN (?4, ?5)
+             RETURNING job_id",
+        )?;
+        let job_ids = statement
+            .query_map(
+                params![
+                    target,
+                    JobState::Lost.to_string(),
+                    now(),
+                    JobState::Dispatched.to_string(),
+                    JobState::Running.to_string(),
+                ],
+                |row| row.get(0),
+            )?
+            .collect();
+        job_ids
+    }
+
+    /// Record a client login, returning the labels it should carry
+    pub fn upsert_client(&self, client_id: &str, name: &str, reported_labels: &[String], inventory: Option<&ClientInventory>, capabilities: Option<&Capabilities>) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO clients (client_id, name, reported_labels, last_seen, inventory, capabilities) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (client_id) DO UPDATE SET name = ?2, reported_labels = ?3, last_seen = ?4, inventory = ?5, capabilities = ?6",
+            params![
+                client_id,
+                name,
+                serde_json::to_string(reported_labels).unwrap(),
+                now(),
+                inventory.map(|inventory| serde_json::to_string(inventory).unwrap()),
+                capabilities.map(|capabilities| serde_json::to_string(capabilities).unwrap()),
+            ],
+        )?;
+        connection.query_row(
+            "SELECT client_id, name, reported_labels, labels, last_seen, inventory, capabilities FROM clients WHERE client_id = ?1",
+            [client_id],
+            row_to_client,
+        ).map(|client| client.labels)
+    }
+
+    /// Override the labels of a known client, returning its updated information
+    pub fn set_client_labels(&self, client_id: &str, labels: Option<&[String]>) -> rusqlite::Result<Option<ClientInfo>> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE clients SET labels = ?2 WHERE client_id = ?1",
+            params![client_id, labels.map(|labels| serde_json::to_string(labels).unwrap())],
+        )?;
+        connection.query_row(
+            "SELECT client_id, name, reported_labels, labels, last_seen, inventory, capabilities FROM clients WHERE client_id = ?1",
+            [client_id],
+            row_to_client,
+        ).optional()
+    }
+
+    /// Every client which logged in at least once
+    pub fn get_client(&self, client_id: &str) -> rusqlite::Result<Option<ClientInfo>> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            "SELECT client_id, name, reported_labels, labels, last_seen, inventory, capabilities FROM clients WHERE client_id = ?1",
+            [client_id],
+            row_to_client,
+        ).optional()
+    }
+
+    pub fn touch_client(&self, client_id: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE clients SET last_seen = ?2 WHERE client_id = ?1",
+            params![client_id, now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_clients(&self) -> rusqlite::Result<Vec<ClientInfo>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT client_id, name, reported_labels, labels, last_seen, inventory, capabilities FROM clients ORDER BY client_id",
+        )?;
+        let clients = statement.query_map([], row_to_client)?.collect();
+        clients
+    }
+
+    /// Sequence number and hash of the last entry written to the audit log
+    pub fn get_audit_head(&self) -> rusqlite::Result<Option<(u64, String)>> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT seq, hash FROM audit_head WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()
+    }
+
+    /// Record the last entry of the audit log, a head further down the chain is never moved back
+    pub fn set_audit_head(&self, seq: u64, hash: &str) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO audit_head (id, seq, hash) VALUES (1, ?1, ?2)
+             ON CONFLICT (id) DO UPDATE SET seq = excluded.seq, hash = excluded.hash
+             WHERE excluded.seq > audit_head.seq",
+            params![seq, hash],
+        )?;
+        Ok(())
+    }
+
+    /// Jobs dispatched to a client which didn't answer yet
+    pub fn count_in_flight(&self) -> rusqlite::Result<usize> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM jobs WHERE state IN (?1, ?2)",
+            params![JobState::Dispatched.to_string(), JobState::Running.to_string()],
+            |row| row.get(0),
+        )
+    }
+
+    /// Mark every job still waiting for a response as lost, returning their IDs
+    pub fn mark_all_in_flight_lost(&self) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "UPDATE jobs SET state = ?1, finished_at = ?2
+             WHERE state IN (?3, ?4)
+             RETURNING job_id",
+        )?;
+        let job_ids = statement
+            .query_map(
+                params![
+                    JobState::Lost.to_string(),
+                    now(),
+                    JobState::Dispatched.to_string(),
+                    JobState::Running.to_string(),
+                ],
+                |row| row.get(0),
+            )?
+            .collect();
+        job_ids
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn row_to_job(row: &Row) -> rusqlite::Result<JobRecord> {
+    let params: String = row.get("params")?;
+    let state: String = row.get("state")?;
+    let signed: Option<String> = row.get("signed")?;
+
+    Ok(JobRecord {
+        job_id: row.get("job_id")?,
+        batch_id: row.get("batch_id")?,
+        admin: row.get("admin")?,
+        target: row.get("target")?,
+        module: row.get("module")?,
+        params: serde_json::from_str(&params).unwrap_or(Value::Null),
+        state: JobState::from_str(&state).unwrap_or(JobState::Lost),
+        created_at: row.get("created_at")?,
+        expires_at: row.get("expires_at")?,
+        dispatched_at: row.get("dispatched_at")?,
+        started_at: row.get("started_at")?,
+        finished_at: row.get("finished_at")?,
+        output: row.get("output")?,
+        signed: signed.and_then(|signed| serde_json::from_str(&signed).ok()),
+    })
+}
+
+/// Admin overrides take precedence over the labels reported by the client
+fn row_to_client(row: &Row) -> rusqlite::Result<ClientInfo> {
+    let reported_labels: String = row.get("reported_labels")?;
+    let labels: Option<String> = row.get("labels")?;
+    let inventory: Option<String> = row.get("inventory")?;
+    let capabilities: Option<String> = row.get("capabilities")?;
+
+    Ok(ClientInfo {
+        id: row.get("client_id")?,
+        name: row.get("name")?,
+        labels: serde_json::from_str(labels.as_ref().unwrap_or(&reported_labels)).unwrap_or_default(),
+        last_seen: row.get("last_seen")?,
+        latency_ms: None,
+        inventory: inventory.and_then(|inventory| serde_json::from_str(&inventory).ok()),
+        capabilities: capabilities.and_then(|capabilities| serde_json::from_str(&capabilities).ok()),
+    })
+}
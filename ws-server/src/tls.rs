@@ -0,0 +1,223 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use futures_util::Stream;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{any_supported_type, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::{Filter, Reply};
+
+/// How often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+/// Connections which didn't complete their handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, which keeps failing while the process is out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Serves the certificate loaded from the configured files, reloading it when they change
+/// so that renewed certificates are picked up without restarting the server
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<LoadedCert>,
+}
+
+struct LoadedCert {
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<CertResolver>, String> {
+        let modified = last_modified(cert_path, key_path);
+        let key = load_certified_key(cert_path, key_path)?;
+
+        Ok(Arc::new(CertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(LoadedCert { key, modified }),
+        }))
+    }
+
+    /// Periodically reload the certificate when its files were modified
+    pub fn spawn_reload(self: &Arc<Self>) {
+        let resolver = self.clone();
+
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                resolver.reload_if_modified();
+            }
+        });
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        if modified == self.current.read().unwrap().modified {
+            return;
+        }
+
+        // keep serving the previous certificate until the files are valid again
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = LoadedCert { key, modified };
+                tracing::info!("Reloaded TLS certificate {}", self.cert_path.display());
+            }
+            Err(e) => tracing::error!("Unable to reload TLS certificate: {}", e),
+        }
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().key.clone())
+    }
+}
+
+/// Peer address of a connection accepted by the TLS listener, set on each of its requests
+#[derive(Debug, Clone, Copy)]
+struct PeerAddress(SocketAddr);
+
+/// Address of the peer, for plain and TLS connections alike
+pub fn remote_address() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddress>())
+        .map(|remote: Option<SocketAddr>, peer: Option<PeerAddress>| remote.or(peer.map(|peer| peer.0)))
+}
+
+/// Serve `routes` over the TLS connections of `listener` until `shutdown` resolves.
+/// warp doesn't know the peer address of the connections it doesn't accept itself,
+/// it is handed to the routes through `remote_address` instead
+pub async fn serve<F, S>(routes: F, listener: TcpListener, config: ServerConfig, shutdown: S)
+where
+    S: Future<Output = ()> + Clone + Send + 'static,
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+        let address = stream.get_ref().0.peer_addr().ok();
+        let service = warp::service(routes.clone());
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request| {
+                if let Some(address) = address {
+                    request.extensions_mut().insert(PeerAddress(address));
+                }
+                service.clone().call(request)
+            }))
+        }
+    });
+
+    let accept = warp::hyper::server::accept::from_stream(incoming(listener, config, shutdown.clone()));
+    if let Err(e) = warp::hyper::Server::builder(accept).serve(make_service).with_graceful_shutdown(shutdown).await {
+        tracing::error!("TLS server error: {}", e);
+    }
+}
+
+/// Accept TCP connections and yield them once their TLS handshake completed,
+/// handshakes run concurrently so that a slow client doesn't hold back the others.
+/// The listener is closed once `shutdown` resolves
+fn incoming(
+    listener: TcpListener,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::task::spawn(async move {
+        tokio::pin!(shutdown);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
+            let (stream, address) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Unable to accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::task::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream);
+                    }
+                    Ok(Err(e)) => tracing::info!("TLS handshake with {} failed: {}", address, e),
+                    Err(_) => tracing::info!("TLS handshake with {} timed out", address),
+                }
+            });
+        }
+    });
+
+    UnboundedReceiverStream::new(rx).map(Ok)
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>, String> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|e| format!("unable to parse {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate found in {}", cert_path.display()));
+    }
+
+    // the key file may also hold certificates or parameters, use its first private key
+    let mut key_file = open(key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_file) {
+            Ok(Some(rustls_pemfile::Item::PKCS8Key(key)))
+            | Ok(Some(rustls_pemfile::Item::RSAKey(key)))
+            | Ok(Some(rustls_pemfile::Item::ECKey(key))) => break key,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("no private key found in {}", key_path.display())),
+            Err(e) => return Err(format!("unable to parse {}: {}", key_path.display(), e)),
+        }
+    };
+
+    let signing_key = any_supported_type(&PrivateKey(key))
+        .map_err(|e| format!("unsupported private key {}: {}", key_path.display(), e))?;
+
+    Ok(Arc::new(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        signing_key,
+    )))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))
+}
+
+/// Latest modification time of the certificate and key files
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    [cert_path, key_path]
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max()
+}